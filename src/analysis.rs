@@ -0,0 +1,374 @@
+//! Analyses over the optimized IR, shared by the backends and the CLI.
+
+use crate::parser::{Node, NodeKind, Span};
+use std::ops::RangeInclusive;
+
+/// What is known about how a loop runs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoopClass {
+    /// The body moves the pointer by zero in total.
+    Balanced,
+    /// The body is balanced and always leaves the loop cell zero, so the loop
+    /// runs at most once and needs no back edge.
+    Conditional,
+    /// The body is balanced and never changes the loop cell, so the loop
+    /// never terminates once entered, e.g. `[]` or `[>+<]`.
+    Infinite,
+    Unknown,
+}
+
+/// Classifies the loop running `body`, taking cells at different offsets to
+/// be different, which does not hold on a circular tape.
+pub fn classify(body: &[Node]) -> LoopClass {
+    if net_move(body) != Some(0) {
+        LoopClass::Unknown
+    } else if !may_write(body, 0) {
+        LoopClass::Infinite
+    } else if leaves_zero(body) {
+        LoopClass::Conditional
+    } else {
+        LoopClass::Balanced
+    }
+}
+
+/// Every loop in `code`, outermost first, with the span of its `[`.
+pub fn loops(code: &[Node]) -> Vec<(Span, LoopClass)> {
+    let mut result = Vec::new();
+    collect_loops(code, &mut result);
+    result
+}
+
+fn collect_loops(code: &[Node], result: &mut Vec<(Span, LoopClass)>) {
+    for node in code {
+        if let NodeKind::Loop(body) = &node.kind {
+            result.push((node.span, classify(body)));
+            collect_loops(body, result);
+        }
+    }
+}
+
+/// Lowest and highest cell a program can touch or move the pointer to,
+/// relative to the starting cell, or `None` when that depends on cell values,
+/// i.e. the program has an unbalanced loop or a scan.
+pub fn tape_range(code: &[Node]) -> Option<RangeInclusive<isize>> {
+    let mut range = 0..=0;
+    cells_touched(code, 0, &mut range)?;
+    Some(range)
+}
+
+/// Tape length a program provably stays within, if its range is known and
+/// never reaches below the starting cell. Accesses need no bounds checks on
+/// a tape at least this long.
+pub fn required_tape_len(code: &[Node]) -> Option<usize> {
+    tape_range(code)
+        .filter(|range| *range.start() >= 0)
+        .map(|range| *range.end() as usize + 1)
+}
+
+/// Upper bound on the distance between consecutive cell accesses, in cells,
+/// counting the starting cell as accessed. A program leaving the tape first
+/// touches a cell at most this far beyond its end.
+pub fn max_reach(code: &[Node]) -> usize {
+    let mut reach = 0;
+    reach_of(code, 0, &mut reach);
+    reach
+}
+
+/// Raises `reach` to cover the accesses of `code`, entered with the pointer
+/// up to `drift` cells from the last access, and returns that bound on exit.
+fn reach_of(code: &[Node], mut drift: usize, reach: &mut usize) -> usize {
+    for node in code {
+        match node.kind {
+            NodeKind::Move(n) => drift = drift.saturating_add(n.unsigned_abs()),
+            NodeKind::Add { offset, .. }
+            | NodeKind::Set { offset, .. }
+            | NodeKind::Write { offset }
+            | NodeKind::Read { offset } => access(reach, &mut drift, offset),
+            NodeKind::MulAdd { offset, .. } => {
+                access(reach, &mut drift, 0);
+                access(reach, &mut drift, offset);
+            }
+            NodeKind::Output(_) => {}
+            NodeKind::Scan(stride) => {
+                access(reach, &mut drift, 0);
+                *reach = (*reach).max(stride.unsigned_abs());
+                drift = 0;
+            }
+            NodeKind::Loop(ref body) => {
+                // the loop cell is tested on entry and at the back edge,
+                // which a conditional loop does not have
+                access(reach, &mut drift, 0);
+                drift = reach_of(body, 0, reach);
+                if classify(body) != LoopClass::Conditional {
+                    *reach = (*reach).max(drift);
+                    drift = 0;
+                }
+            }
+        }
+    }
+    drift
+}
+
+/// Records an access at `offset` from the pointer.
+fn access(reach: &mut usize, drift: &mut usize, offset: isize) {
+    *reach = (*reach).max(drift.saturating_add(offset.unsigned_abs()));
+    *drift = offset.unsigned_abs();
+}
+
+/// Widens `range` to cover the cells `code` touches or moves the pointer to
+/// with the pointer at `shift`, returning the pointer's shift afterwards.
+fn cells_touched(
+    code: &[Node],
+    mut shift: isize,
+    range: &mut RangeInclusive<isize>,
+) -> Option<isize> {
+    for node in code {
+        match node.kind {
+            NodeKind::Move(n) => {
+                shift += n;
+                widen(range, shift);
+            }
+            NodeKind::Add { offset, .. }
+            | NodeKind::Set { offset, .. }
+            | NodeKind::Write { offset }
+            | NodeKind::Read { offset } => widen(range, shift + offset),
+            NodeKind::MulAdd { offset, .. } => {
+                widen(range, shift);
+                widen(range, shift + offset);
+            }
+            NodeKind::Output(_) => {}
+            NodeKind::Scan(_) => return None,
+            NodeKind::Loop(ref body) => {
+                widen(range, shift);
+                if cells_touched(body, shift, range)? != shift {
+                    return None;
+                }
+            }
+        }
+    }
+    Some(shift)
+}
+
+fn widen(range: &mut RangeInclusive<isize>, cell: isize) {
+    *range = cell.min(*range.start())..=cell.max(*range.end());
+}
+
+/// Net pointer movement of `code`, or `None` if it depends on cell values.
+fn net_move(code: &[Node]) -> Option<isize> {
+    code.iter().try_fold(0, |shift, node| match &node.kind {
+        NodeKind::Move(n) => Some(shift + n),
+        NodeKind::Scan(_) => None,
+        NodeKind::Loop(body) => (net_move(body)? == 0).then_some(shift),
+        _ => Some(shift),
+    })
+}
+
+/// Whether balanced `code` may change the cell at `cell` from its start.
+fn may_write(code: &[Node], cell: isize) -> bool {
+    let mut shift = 0;
+    for node in code {
+        match node.kind {
+            NodeKind::Move(n) => shift += n,
+            NodeKind::Add { offset, .. }
+            | NodeKind::Set { offset, .. }
+            | NodeKind::MulAdd { offset, .. }
+            | NodeKind::Read { offset } => {
+                if shift + offset == cell {
+                    return true;
+                }
+            }
+            NodeKind::Loop(ref body) => {
+                if may_write(body, cell - shift) {
+                    return true;
+                }
+            }
+            NodeKind::Scan(_) => return true,
+            NodeKind::Write { .. } | NodeKind::Output(_) => {}
+        }
+    }
+    false
+}
+
+/// Whether balanced `code` always leaves its starting cell zero.
+fn leaves_zero(code: &[Node]) -> bool {
+    let mut shift = 0;
+    let mut zeroed = false;
+    for node in code {
+        match node.kind {
+            NodeKind::Move(n) => shift += n,
+            NodeKind::Set { offset, value } if shift + offset == 0 => zeroed = value == 0,
+            NodeKind::Add { offset, .. }
+            | NodeKind::Set { offset, .. }
+            | NodeKind::MulAdd { offset, .. }
+            | NodeKind::Read { offset } => {
+                if shift + offset == 0 {
+                    zeroed = false;
+                }
+            }
+            // a loop over the starting cell exits with it zero
+            NodeKind::Loop(_) if shift == 0 => zeroed = true,
+            NodeKind::Loop(ref body) => {
+                if may_write(body, -shift) {
+                    zeroed = false;
+                }
+            }
+            NodeKind::Scan(_) => return false,
+            NodeKind::Write { .. } | NodeKind::Output(_) => {}
+        }
+    }
+    zeroed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn node(kind: NodeKind) -> Node {
+        Node {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    /// Class of the loop `source` consists of.
+    fn class_of(source: &str) -> LoopClass {
+        match &parse(source).unwrap()[..] {
+            [Node {
+                kind: NodeKind::Loop(body),
+                ..
+            }] => classify(body),
+            code => panic!("expected a single loop, got {:?}", code),
+        }
+    }
+
+    #[test]
+    fn loops_leaving_their_cell_zero_are_conditional() {
+        assert_eq!(class_of("[[-]>>+<<]"), LoopClass::Conditional);
+        assert_eq!(class_of("[>+<[-]]"), LoopClass::Conditional);
+        // `[-]` after clear-loop
+        let body = [
+            node(NodeKind::Set {
+                offset: 0,
+                value: 0,
+            }),
+            node(NodeKind::Add {
+                offset: 1,
+                value: 1,
+            }),
+        ];
+        assert_eq!(classify(&body), LoopClass::Conditional);
+    }
+
+    #[test]
+    fn loops_never_changing_their_cell_are_infinite() {
+        assert_eq!(class_of("[]"), LoopClass::Infinite);
+        assert_eq!(class_of("[>+<]"), LoopClass::Infinite);
+        assert_eq!(class_of("[.>[-]<]"), LoopClass::Infinite);
+    }
+
+    #[test]
+    fn other_balanced_loops_are_balanced() {
+        assert_eq!(class_of("[-]"), LoopClass::Balanced);
+        assert_eq!(class_of("[->+<]"), LoopClass::Balanced);
+        // input may store anything
+        assert_eq!(class_of("[,]"), LoopClass::Balanced);
+        // the store is undone before the loop cell is tested
+        assert_eq!(class_of("[[-]+]"), LoopClass::Balanced);
+    }
+
+    #[test]
+    fn unbalanced_loops_are_unknown() {
+        assert_eq!(class_of("[>]"), LoopClass::Unknown);
+        assert_eq!(class_of("[-<]"), LoopClass::Unknown);
+        assert_eq!(class_of("[[-]>[>]<]"), LoopClass::Unknown);
+        assert_eq!(classify(&[node(NodeKind::Scan(1))]), LoopClass::Unknown);
+    }
+
+    #[test]
+    fn loops_are_listed_outermost_first() {
+        let code = parse("[]\n[[->+<]-]\n[>]").unwrap();
+        let classes: Vec<_> = loops(&code)
+            .into_iter()
+            .map(|(span, class)| (span.line, span.column, class))
+            .collect();
+        assert_eq!(
+            classes,
+            [
+                (1, 1, LoopClass::Infinite),
+                (2, 1, LoopClass::Balanced),
+                (2, 2, LoopClass::Balanced),
+                (3, 1, LoopClass::Unknown),
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_cover_accesses_and_pointer_positions() {
+        let range = |source| tape_range(&parse(source).unwrap());
+        assert_eq!(range(""), Some(0..=0));
+        assert_eq!(range(">>+<<<-"), Some(-1..=2));
+        // the pointer visits cells it never touches
+        assert_eq!(range("<>>>"), Some(-1..=2));
+        assert_eq!(range("+[->>+<<]"), Some(0..=2));
+        assert_eq!(range("+[->>[-<+>]<<]"), Some(0..=2));
+    }
+
+    #[test]
+    fn ranges_of_scans_and_unbalanced_loops_are_unbounded() {
+        let range = |source| tape_range(&parse(source).unwrap());
+        assert_eq!(range("+[>]"), None);
+        assert_eq!(range(">,[>,]"), None);
+        assert_eq!(range("+[->+<[>]]"), None);
+        assert_eq!(tape_range(&[node(NodeKind::Scan(-2))]), None);
+    }
+
+    #[test]
+    fn required_lengths_need_ranges_starting_at_the_origin() {
+        let len = |source| required_tape_len(&parse(source).unwrap());
+        assert_eq!(len(""), Some(1));
+        assert_eq!(len(">>+[-]"), Some(3));
+        assert_eq!(len("<+"), None);
+        assert_eq!(len("+[>]"), None);
+    }
+
+    #[test]
+    fn reach_is_the_farthest_step_between_accesses() {
+        let reach = |source| max_reach(&parse(source).unwrap());
+        assert_eq!(reach(""), 0);
+        assert_eq!(reach("+>+>+"), 1);
+        assert_eq!(reach(">>>+<<<<<+"), 5);
+        // moves without accesses in between add up
+        assert_eq!(reach(">>><>>+"), 6);
+        let offsets = [
+            node(NodeKind::Move(2)),
+            node(NodeKind::MulAdd {
+                offset: -6,
+                factor: 1,
+            }),
+            node(NodeKind::Write { offset: 3 }),
+        ];
+        assert_eq!(max_reach(&offsets), 9);
+        assert_eq!(max_reach(&[node(NodeKind::Scan(-8))]), 8);
+    }
+
+    #[test]
+    fn reach_covers_the_back_edges_of_loops() {
+        let reach = |source| max_reach(&parse(source).unwrap());
+        // back to the loop cell from the last access in the body
+        assert_eq!(reach("+[>>>>>]"), 5);
+        assert_eq!(reach("+[-<<<+>>>>>>>>]"), 8);
+        // a loop not entered still tests its cell
+        assert_eq!(reach(">>>[-]"), 3);
+    }
+
+    #[test]
+    fn reach_carries_across_conditional_loops() {
+        let reach = |source| max_reach(&parse(source).unwrap());
+        // a conditional loop has no back edge, so after it the pointer
+        // keeps moving away from the last access in its body
+        assert_eq!(reach("[[-]>>>+<<<]>>>>+"), 7);
+        // a balanced loop tests its cell again before leaving
+        assert_eq!(reach("[>>>+<<<-]>>>>+"), 4);
+    }
+}
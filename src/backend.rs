@@ -0,0 +1,74 @@
+use crate::error::BfError;
+use crate::io::{Io, MemoryIo};
+use crate::options::Options;
+use crate::parser::{parse, Node};
+use crate::passes::optimize;
+use crate::tape::Tape;
+use crate::{crane_jit, fast_jit, interpreter};
+
+/// An execution engine holding a compiled program.
+pub trait Backend {
+    /// Parses, optimizes and compiles `source`.
+    fn compile(source: &str, options: &Options) -> Result<Self, BfError>
+    where
+        Self: Sized,
+    {
+        let (code, _) = optimize(parse(source)?, options)?;
+        Self::compile_ir(&code, options)
+    }
+
+    /// Compiles already parsed and optimized nodes.
+    fn compile_ir(code: &[Node], options: &Options) -> Result<Self, BfError>
+    where
+        Self: Sized;
+
+    /// Machine code generated for the program, if the backend generates any.
+    fn code(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Runs the program on `tape`, reading input from and writing output to `io`.
+    fn run(&mut self, tape: &mut Tape, io: &mut dyn Io) -> Result<(), BfError>;
+
+    /// A fresh tape for the program, laid out as its options say.
+    fn new_tape(&self) -> Result<Tape, BfError>;
+
+    /// Runs the program on a fresh tape with `input` and returns its output.
+    fn run_with_input(&mut self, input: &[u8]) -> Result<Vec<u8>, BfError> {
+        let mut tape = self.new_tape()?;
+        let mut io = MemoryIo::new(input);
+        self.run(&mut tape, &mut io)?;
+        Ok(io.into_output())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    Interpreter,
+    FastJit,
+    CraneJit,
+}
+
+impl BackendKind {
+    /// Compiles `source` with the selected backend.
+    pub fn compile(self, source: &str, options: &Options) -> Result<Box<dyn Backend>, BfError> {
+        Ok(match self {
+            BackendKind::Interpreter => {
+                Box::new(interpreter::Interpreter::compile(source, options)?)
+            }
+            BackendKind::FastJit => Box::new(fast_jit::Program::compile(source, options)?),
+            BackendKind::CraneJit => Box::new(crane_jit::Program::compile(source, options)?),
+        })
+    }
+
+    /// Compiles already parsed and optimized nodes with the selected backend.
+    pub fn compile_ir(self, code: &[Node], options: &Options) -> Result<Box<dyn Backend>, BfError> {
+        Ok(match self {
+            BackendKind::Interpreter => {
+                Box::new(interpreter::Interpreter::compile_ir(code, options)?)
+            }
+            BackendKind::FastJit => Box::new(fast_jit::Program::compile_ir(code, options)?),
+            BackendKind::CraneJit => Box::new(crane_jit::Program::compile_ir(code, options)?),
+        })
+    }
+}
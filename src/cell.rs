@@ -0,0 +1,169 @@
+//! Cell widths and the integer types holding cells.
+
+use crate::error::BfError;
+use crate::parser::Span;
+use crate::scan;
+
+/// Number of bits in a cell. Arithmetic on cells wraps at this width.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CellWidth {
+    #[default]
+    #[value(name = "8")]
+    Bits8,
+    #[value(name = "16")]
+    Bits16,
+    #[value(name = "32")]
+    Bits32,
+    #[value(name = "64")]
+    Bits64,
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::Bits8 => 8,
+            CellWidth::Bits16 => 16,
+            CellWidth::Bits32 => 32,
+            CellWidth::Bits64 => 64,
+        }
+    }
+
+    /// Bytes a cell takes on the tape.
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// Largest value a cell holds.
+    pub fn max(self) -> u64 {
+        u64::MAX >> (64 - self.bits())
+    }
+
+    /// Bytes the data pointer moves by for a move of `cells` cells by the
+    /// node at `span`.
+    pub(crate) fn move_bytes(self, cells: isize, span: Span) -> Result<i64, BfError> {
+        (cells as i64)
+            .checked_mul(self.bytes() as i64)
+            .ok_or_else(|| too_large(cells, span))
+    }
+
+    /// Byte displacement of the cell at `offset` from the pointer, as
+    /// generated code encodes it in an instruction.
+    pub(crate) fn displacement(self, offset: isize, span: Span) -> Result<i32, BfError> {
+        let bytes = self.move_bytes(offset, span)?;
+        i32::try_from(bytes).map_err(|_| too_large(offset, span))
+    }
+}
+
+fn too_large(offset: isize, span: Span) -> BfError {
+    BfError::Compile(format!("{}: cell offset {} is too large", span, offset))
+}
+
+/// What arithmetic does when it would take a cell below zero or above its
+/// largest value.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Overflow {
+    /// Wrap around modulo the cell width.
+    #[default]
+    Wrap,
+    /// Stop with [`BfError::CellOverflow`](crate::BfError::CellOverflow).
+    Error,
+    /// Clamp to zero or the largest value.
+    Saturate,
+}
+
+impl Overflow {
+    /// Adds `n` to a cell holding `value`, whose largest value is `max`.
+    /// Returns `None` if that overflows in error mode.
+    pub(crate) fn add(self, value: u64, n: i128, max: u64) -> Option<u64> {
+        let sum = value as i128 + n;
+        match self {
+            Overflow::Wrap => Some(sum as u64 & max),
+            Overflow::Error => (0..=max as i128).contains(&sum).then_some(sum as u64),
+            Overflow::Saturate => Some(sum.clamp(0, max as i128) as u64),
+        }
+    }
+}
+
+/// An unsigned integer type holding a cell.
+pub(crate) trait Cell: Copy + PartialEq + 'static {
+    const ZERO: Self;
+
+    /// Truncates `value` to the cell width.
+    fn from_u64(value: u64) -> Self;
+
+    fn to_u64(self) -> u64;
+
+    fn wrapping_add(self, other: Self) -> Self;
+
+    fn wrapping_mul(self, other: Self) -> Self;
+
+    /// Returns the index of the first zero cell among `start`,
+    /// `start + stride`, ... that lies inside `cells`.
+    fn find_zero(cells: &[Self], start: usize, stride: isize) -> Option<usize> {
+        scan::find_zero_scalar(cells, start, stride)
+    }
+}
+
+macro_rules! impl_cell {
+    ($($ty:ty),*) => {$(
+        impl Cell for $ty {
+            const ZERO: Self = 0;
+
+            fn from_u64(value: u64) -> Self {
+                value as $ty
+            }
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn wrapping_add(self, other: Self) -> Self {
+                <$ty>::wrapping_add(self, other)
+            }
+
+            fn wrapping_mul(self, other: Self) -> Self {
+                <$ty>::wrapping_mul(self, other)
+            }
+        }
+    )*};
+}
+
+impl_cell!(u16, u32, u64);
+
+impl Cell for u8 {
+    const ZERO: Self = 0;
+
+    fn from_u64(value: u64) -> Self {
+        value as u8
+    }
+
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        u8::wrapping_add(self, other)
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        u8::wrapping_mul(self, other)
+    }
+
+    fn find_zero(cells: &[u8], start: usize, stride: isize) -> Option<usize> {
+        scan::find_zero(cells, start, stride)
+    }
+}
+
+/// Bytes output for a cell holding `value`: its low byte, or with `utf8` the
+/// UTF-8 encoding of the code point, U+FFFD if it is not a valid one.
+pub(crate) fn encode(value: u64, utf8: bool, buf: &mut [u8; 4]) -> &[u8] {
+    if !utf8 {
+        buf[0] = value as u8;
+        return &buf[..1];
+    }
+    let c = u32::try_from(value)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
+    c.encode_utf8(buf).as_bytes()
+}
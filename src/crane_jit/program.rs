@@ -1,174 +1,510 @@
-use crate::parser::{parse, Node};
-use crate::{read, write, INIT_MEMORY_SIZE};
+use crate::analysis::{self, LoopClass};
+use crate::backend::Backend;
+use crate::cell::{CellWidth, Overflow};
+use crate::error::BfError;
+use crate::guard::{self, Bounds};
+use crate::io::Io;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind, Span};
+use crate::runtime::{self, cell_overflow, limit_exceeded, out_of_bounds, read, write, write_data};
+use crate::tape::Tape;
 use cranelift::codegen::control::ControlPlane;
-use cranelift::codegen::ir::{Function, UserFuncName};
+use cranelift::codegen::ir::{Function, Inst, SigRef, UserFuncName};
 use cranelift::codegen::{verify_function, Context};
-use cranelift::prelude::isa::CallConv;
-use cranelift::prelude::types::I8;
+use cranelift::prelude::isa::{CallConv, OwnedTargetIsa};
+use cranelift::prelude::types::I64;
 use cranelift::prelude::*;
+use std::ops::RangeInclusive;
 
 pub struct Program {
     bytes: Vec<u8>,
+    /// Output computed at compile time, referenced by the generated code.
+    data: Vec<u8>,
+    bounds: Bounds,
+    tape_range: Option<RangeInclusive<isize>>,
+    options: Options,
 }
 
 impl Program {
-    pub fn new(source: &str) -> Result<Program, String> {
-        let mut builder = settings::builder();
-        builder.set("opt_level", "speed").unwrap();
-        let flags = settings::Flags::new(builder);
+    /// Cranelift IR generated for `code`, in its textual form.
+    pub fn clif(code: &[Node], options: &Options) -> Result<String, BfError> {
+        let bounds = Bounds::of(code, options)?;
+        let (func, _, _) = build_function(code, options, bounds.checked())?;
+        Ok(func.display().to_string())
+    }
+}
 
-        let isa_builder = cranelift_native::builder()
-            .unwrap_or_else(|msg| panic!("host machine is not a supported target: {}", msg));
-        let isa = isa_builder.finish(flags).unwrap();
+impl Backend for Program {
+    fn compile_ir(code: &[Node], options: &Options) -> Result<Program, BfError> {
+        let bounds = Bounds::of(code, options)?;
+        let (func, isa, data) = build_function(code, options, bounds.checked())?;
+        verify_function(&func, &*isa).map_err(|errors| BfError::Compile(errors.to_string()))?;
 
-        let pointer_type = isa.pointer_type();
+        let mut ctx = Context::for_function(func);
+        let mut control_plane = ControlPlane::default();
+        let compiled = ctx
+            .compile(&*isa, &mut control_plane)
+            .map_err(|err| BfError::Compile(err.inner.to_string()))?;
+        let bytes = compiled.code_buffer().to_vec();
+        Ok(Program {
+            bytes,
+            data,
+            bounds,
+            tape_range: analysis::tape_range(code),
+            options: *options,
+        })
+    }
 
-        // receive memory address as parameter, and return pointer to io::Error
-        let mut sig = Signature::new(CallConv::SystemV);
-        sig.params.push(AbiParam::new(pointer_type));
-        sig.returns.push(AbiParam::new(pointer_type));
+    fn code(&self) -> Option<&[u8]> {
+        Some(&self.bytes)
+    }
 
-        let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
+    fn new_tape(&self) -> Result<Tape, BfError> {
+        Tape::for_code(self.tape_range.clone(), &self.options, self.bounds.guard())
+    }
 
-        let mut func_ctx = FunctionBuilderContext::new();
-        let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+    fn run(&mut self, tape: &mut Tape, io: &mut dyn Io) -> Result<(), BfError> {
+        let mut buffer = memmap2::MmapOptions::new()
+            .len(self.bytes.len())
+            .map_anon()
+            .map_err(BfError::Memory)?;
+        buffer.copy_from_slice(&self.bytes);
 
-        // create a variable `pointer` (offset from memory address)
-        let pointer = Variable::new(0);
-        builder.declare_var(pointer, pointer_type);
+        let buffer = buffer.make_exec().map_err(BfError::Memory)?;
+        self.bounds.prepare(tape, &self.options)?;
+        let mut context = runtime::Context::new(io, &self.options, &self.data);
+        let result = unsafe {
+            let func: runtime::Entry = std::mem::transmute(buffer.as_ptr());
+            guard::call(func, tape, &mut context)
+        };
+        io.flush()?;
+        result
+    }
+}
 
+/// Builds the function running `code`, along with the host ISA to compile it
+/// for and the constant output the function expects in its runtime context.
+/// With `checked` every pointer move and cell access is checked against the
+/// tape length.
+fn build_function(
+    code: &[Node],
+    options: &Options,
+    checked: bool,
+) -> Result<(Function, OwnedTargetIsa, Vec<u8>), BfError> {
+    let mut builder = settings::builder();
+    builder
+        .set("opt_level", "speed")
+        .map_err(|err| BfError::Compile(err.to_string()))?;
+    let flags = settings::Flags::new(builder);
+
+    let isa_builder = cranelift_native::builder().map_err(|msg| {
+        BfError::Compile(format!("host machine is not a supported target: {}", msg))
+    })?;
+    let isa = isa_builder
+        .finish(flags)
+        .map_err(|err| BfError::Compile(err.to_string()))?;
+
+    let pointer_type = isa.pointer_type();
+
+    // receive memory address, runtime context, memory length and the initial
+    // pointer as parameters, and return pointer to BfError
+    let mut sig = Signature::new(CallConv::SystemV);
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.returns.push(AbiParam::new(pointer_type));
+
+    let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
+
+    let mut func_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+
+    // create a variable `pointer` (offset from memory address)
+    let pointer = Variable::new(0);
+    builder.declare_var(pointer, pointer_type);
+
+    let block = builder.create_block();
+    builder.seal_block(block);
+
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+
+    let memory_address = builder.block_params(block)[0];
+    let context = builder.block_params(block)[1];
+    let tape_len = builder.block_params(block)[2];
+    let origin = builder.block_params(block)[3];
+
+    builder.def_var(pointer, origin);
+
+    let mem_flags = MemFlags::new();
+
+    let (write_sig, write_address) = {
+        let mut write_sig = Signature::new(CallConv::SystemV);
+        write_sig.params.push(AbiParam::new(pointer_type));
+        write_sig.params.push(AbiParam::new(I64));
+        write_sig.returns.push(AbiParam::new(pointer_type));
+        let write_sig = builder.import_signature(write_sig);
+
+        let write_address = write as *const () as i64;
+        let write_address = builder.ins().iconst(pointer_type, write_address);
+        (write_sig, write_address)
+    };
+
+    let (read_sig, read_address) = {
+        let mut read_sig = Signature::new(CallConv::SystemV);
+        read_sig.params.push(AbiParam::new(pointer_type));
+        read_sig.params.push(AbiParam::new(pointer_type));
+        read_sig.returns.push(AbiParam::new(pointer_type));
+        let read_sig = builder.import_signature(read_sig);
+
+        let read_address = read as *const () as i64;
+        let read_address = builder.ins().iconst(pointer_type, read_address);
+        (read_sig, read_address)
+    };
+
+    let (write_data_sig, write_data_address) = {
+        let mut write_data_sig = Signature::new(CallConv::SystemV);
+        write_data_sig.params.push(AbiParam::new(pointer_type));
+        write_data_sig.params.push(AbiParam::new(pointer_type));
+        write_data_sig.params.push(AbiParam::new(pointer_type));
+        write_data_sig.returns.push(AbiParam::new(pointer_type));
+        let write_data_sig = builder.import_signature(write_data_sig);
+
+        let write_data_address = write_data as *const () as i64;
+        let write_data_address = builder.ins().iconst(pointer_type, write_data_address);
+        (write_data_sig, write_data_address)
+    };
+
+    // helpers reporting a failed check at a source position
+    let (report_sig, bounds_address, overflow_address) = {
+        let mut report_sig = Signature::new(CallConv::SystemV);
+        report_sig.params.push(AbiParam::new(pointer_type));
+        report_sig.params.push(AbiParam::new(pointer_type));
+        report_sig.params.push(AbiParam::new(pointer_type));
+        report_sig.returns.push(AbiParam::new(pointer_type));
+        let report_sig = builder.import_signature(report_sig);
+
+        let bounds_address = out_of_bounds as *const () as i64;
+        let bounds_address = builder.ins().iconst(pointer_type, bounds_address);
+        let overflow_address = cell_overflow as *const () as i64;
+        let overflow_address = builder.ins().iconst(pointer_type, overflow_address);
+        (report_sig, bounds_address, overflow_address)
+    };
+
+    let exit_block = builder.create_block();
+    builder.append_block_param(exit_block, pointer_type);
+
+    // reached from loop back edges once the fuel or time runs out
+    let limit_block = options.limits.interrupts().then(|| {
         let block = builder.create_block();
-        builder.seal_block(block);
-
-        builder.append_block_params_for_function_params(block);
-        builder.switch_to_block(block);
-
-        let memory_address = builder.block_params(block)[0];
-
-        // initialize pointer to 0
-        let zero = builder.ins().iconst(pointer_type, 0);
-        builder.def_var(pointer, zero);
+        builder.set_cold_block(block);
+        block
+    });
+
+    let mut translator = Translator {
+        builder,
+        pointer,
+        memory_address,
+        context,
+        write_sig,
+        write_address,
+        read_sig,
+        read_address,
+        write_data_sig,
+        write_data_address,
+        data: Vec::new(),
+        cell_type: Type::int_with_byte_size(options.cell.bytes() as u16).unwrap(),
+        cell: options.cell,
+        overflow: options.overflow,
+        checked,
+        tape_len,
+        report_sig,
+        bounds_address,
+        overflow_address,
+        exit_block,
+        limit_block,
+        mem_flags,
+    };
+    translator.check_interrupts(code.len());
+    translator.translate(code)?;
+    let mut builder = translator.builder;
+    let data = translator.data;
+
+    let zero = builder.ins().iconst(pointer_type, 0);
+    builder.ins().return_(&[zero]);
+
+    if let Some(limit_block) = limit_block {
+        let mut limit_sig = Signature::new(CallConv::SystemV);
+        limit_sig.params.push(AbiParam::new(pointer_type));
+        limit_sig.returns.push(AbiParam::new(pointer_type));
+        let limit_sig = builder.import_signature(limit_sig);
+
+        builder.switch_to_block(limit_block);
+        builder.seal_block(limit_block);
+        let limit_address = limit_exceeded as *const () as i64;
+        let limit_address = builder.ins().iconst(pointer_type, limit_address);
+        let inst = builder
+            .ins()
+            .call_indirect(limit_sig, limit_address, &[context]);
+        let error = builder.inst_results(inst)[0];
+        builder.ins().jump(exit_block, &[error]);
+    }
 
-        let code = parse(source)?;
-        let mut loop_stack = Vec::new();
-        let mem_flags = MemFlags::new();
+    builder.switch_to_block(exit_block);
+    builder.seal_block(exit_block);
 
-        let (write_sig, write_address) = {
-            let mut write_sig = Signature::new(CallConv::SystemV);
-            write_sig.params.push(AbiParam::new(I8));
-            write_sig.returns.push(AbiParam::new(pointer_type));
-            let write_sig = builder.import_signature(write_sig);
+    let result = builder.block_params(exit_block)[0];
+    builder.ins().return_(&[result]);
 
-            let write_address = write as *const () as i64;
-            let write_address = builder.ins().iconst(pointer_type, write_address);
-            (write_sig, write_address)
-        };
+    builder.finalize();
+    Ok((func, isa, data))
+}
 
-        let (read_sig, read_address) = {
-            let mut read_sig = Signature::new(CallConv::SystemV);
-            read_sig.params.push(AbiParam::new(pointer_type));
-            read_sig.returns.push(AbiParam::new(pointer_type));
-            let read_sig = builder.import_signature(read_sig);
+/// Translates nodes into the body of the compiled function.
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    /// Offset of the data pointer from `memory_address`.
+    pointer: Variable,
+    memory_address: Value,
+    context: Value,
+    write_sig: SigRef,
+    write_address: Value,
+    read_sig: SigRef,
+    read_address: Value,
+    write_data_sig: SigRef,
+    write_data_address: Value,
+    /// Constant output collected from `Output` nodes.
+    data: Vec<u8>,
+    /// Type and width of the cells; the pointer and offsets count bytes.
+    cell_type: Type,
+    cell: CellWidth,
+    overflow: Overflow,
+    /// Whether pointer moves and cell accesses are checked against `tape_len`.
+    checked: bool,
+    tape_len: Value,
+    /// Signature of the helpers reporting a failed check.
+    report_sig: SigRef,
+    bounds_address: Value,
+    overflow_address: Value,
+    /// Returns the error passed as its parameter.
+    exit_block: Block,
+    /// Reports the step or time limit exceeded, if the code has to charge fuel
+    /// and poll for cancellation.
+    limit_block: Option<Block>,
+    mem_flags: MemFlags,
+}
 
-            let read_address = read as *const () as i64;
-            let read_address = builder.ins().iconst(pointer_type, read_address);
-            (read_sig, read_address)
+impl Translator<'_> {
+    fn translate(&mut self, code: &[Node]) -> Result<(), BfError> {
+        let is_mul_add = |i: usize| {
+            matches!(
+                code.get(i).map(|node| &node.kind),
+                Some(NodeKind::MulAdd { .. })
+            )
         };
-
-        let exit_block = builder.create_block();
-        builder.append_block_param(exit_block, pointer_type);
-
-        for c in code {
-            match c {
-                Node::Increment(n) => {
-                    let pointer_value = builder.use_var(pointer);
-                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
-                    let cell_value = builder.ins().load(I8, mem_flags, cell_address, 0);
-                    let cell_value = builder.ins().iadd_imm(cell_value, n as i64);
-                    builder.ins().store(mem_flags, cell_value, cell_address, 0);
-                }
-                Node::Decrement(n) => {
-                    let pointer_value = builder.use_var(pointer);
-                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
-                    let cell_value = builder.ins().load(I8, mem_flags, cell_address, 0);
-                    let cell_value = builder.ins().iadd_imm(cell_value, -(n as i64));
-                    builder.ins().store(mem_flags, cell_value, cell_address, 0);
-                }
-                Node::Prev(n) => {
-                    let pointer_value = builder.use_var(pointer);
-                    let pointer_value = builder.ins().iadd_imm(pointer_value, -(n as i64));
-                    builder.def_var(pointer, pointer_value);
+        let mut mul_after_block = None;
+        let mem_flags = self.mem_flags;
+        let cell_type = self.cell_type;
+        let cell = self.cell;
+
+        for (i, c) in code.iter().enumerate() {
+            match c.kind {
+                NodeKind::Add { offset, value } => {
+                    let offset = cell.displacement(offset, c.span)?;
+                    self.check_bounds(offset, c.span);
+                    let cell_address = self.cell_address();
+                    let cell_value =
+                        self.builder
+                            .ins()
+                            .load(cell_type, mem_flags, cell_address, offset);
+                    let cell_value = if self.overflow == Overflow::Wrap {
+                        self.builder.ins().iadd_imm(cell_value, value)
+                    } else {
+                        self.add_checked(cell_value, None, value, c.span)
+                    };
+                    self.builder
+                        .ins()
+                        .store(mem_flags, cell_value, cell_address, offset);
                 }
-                Node::Next(n) => {
-                    let pointer_value = builder.use_var(pointer);
-                    let pointer_value = builder.ins().iadd_imm(pointer_value, n as i64);
-                    builder.def_var(pointer, pointer_value);
+                NodeKind::Set { offset, value } => {
+                    let offset = cell.displacement(offset, c.span)?;
+                    self.check_bounds(offset, c.span);
+                    let cell_address = self.cell_address();
+                    let builder = &mut self.builder;
+                    let value = value & cell.max() as i64;
+                    let cell_value = builder.ins().iconst(cell_type, value);
+                    builder
+                        .ins()
+                        .store(mem_flags, cell_value, cell_address, offset);
                 }
-                Node::Write => {
-                    let pointer_value = builder.use_var(pointer);
-                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
-                    let cell_value = builder.ins().load(I8, mem_flags, cell_address, 0);
-
-                    let inst = builder
+                NodeKind::MulAdd { offset, factor } => {
+                    let offset = cell.displacement(offset, c.span)?;
+                    let first = i == 0 || !is_mul_add(i - 1);
+                    if first {
+                        self.check_bounds(0, c.span);
+                    }
+                    let cell_address = self.cell_address();
+                    let cell_value = self
+                        .builder
                         .ins()
-                        .call_indirect(write_sig, write_address, &[cell_value]);
-                    let result = builder.inst_results(inst)[0];
-
-                    let after_block = builder.create_block();
-
+                        .load(cell_type, mem_flags, cell_address, 0);
+
+                    if first {
+                        let builder = &mut self.builder;
+                        let body_block = builder.create_block();
+                        let after_block = builder.create_block();
+                        builder
+                            .ins()
+                            .brif(cell_value, body_block, &[], after_block, &[]);
+                        builder.seal_block(body_block);
+                        builder.switch_to_block(body_block);
+                        mul_after_block = Some(after_block);
+                    }
+
+                    self.check_bounds(offset, c.span);
+                    let target_value =
+                        self.builder
+                            .ins()
+                            .load(cell_type, mem_flags, cell_address, offset);
+                    let target_value = if self.overflow == Overflow::Wrap {
+                        let product = self.builder.ins().imul_imm(cell_value, factor);
+                        self.builder.ins().iadd(target_value, product)
+                    } else {
+                        self.add_checked(target_value, Some(cell_value), factor, c.span)
+                    };
+                    let builder = &mut self.builder;
                     builder
                         .ins()
-                        .brif(result, exit_block, &[result], after_block, &[]);
-
-                    builder.seal_block(after_block);
-                    builder.switch_to_block(after_block);
+                        .store(mem_flags, target_value, cell_address, offset);
+
+                    if !is_mul_add(i + 1) {
+                        if let Some(after_block) = mul_after_block.take() {
+                            builder.ins().jump(after_block, &[]);
+                            builder.seal_block(after_block);
+                            builder.switch_to_block(after_block);
+                        }
+                    }
                 }
-                Node::Read => {
-                    let pointer_value = builder.use_var(pointer);
-                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
-
-                    let inst = builder
-                        .ins()
-                        .call_indirect(read_sig, read_address, &[cell_address]);
-                    let result = builder.inst_results(inst)[0];
-
-                    let after_block = builder.create_block();
-
+                NodeKind::Scan(stride) => {
+                    let stride = cell.displacement(stride, c.span)?;
+                    let header_block = self.builder.create_block();
+                    let step_block = self.builder.create_block();
+                    let after_block = self.builder.create_block();
+                    self.builder.ins().jump(header_block, &[]);
+
+                    self.builder.switch_to_block(header_block);
+                    self.check_bounds(0, c.span);
+                    let pointer_value = self.builder.use_var(self.pointer);
+                    let cell_address = self.cell_address();
+                    let builder = &mut self.builder;
+                    let cell_value = builder.ins().load(cell_type, mem_flags, cell_address, 0);
                     builder
                         .ins()
-                        .brif(result, exit_block, &[result], after_block, &[]);
+                        .brif(cell_value, step_block, &[], after_block, &[]);
+
+                    builder.switch_to_block(step_block);
+                    builder.seal_block(step_block);
+                    let pointer_value = builder.ins().iadd_imm(pointer_value, stride as i64);
+                    builder.def_var(self.pointer, pointer_value);
+                    builder.ins().jump(header_block, &[]);
+                    builder.seal_block(header_block);
 
-                    builder.seal_block(after_block);
                     builder.switch_to_block(after_block);
+                    builder.seal_block(after_block);
                 }
-                Node::LoopBegin => {
-                    let inner_block = builder.create_block();
-                    let after_block = builder.create_block();
-
-                    let pointer_value = builder.use_var(pointer);
-                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
-                    let cell_value = builder.ins().load(I8, MemFlags::new(), cell_address, 0);
-
-                    builder
+                NodeKind::Move(n) => {
+                    let builder = &mut self.builder;
+                    let pointer_value = builder.use_var(self.pointer);
+                    let n = cell.move_bytes(n, c.span)?;
+                    let pointer_value = builder.ins().iadd_imm(pointer_value, n);
+                    builder.def_var(self.pointer, pointer_value);
+                    self.check_bounds(0, c.span);
+                }
+                NodeKind::Write { offset } => {
+                    let offset = cell.displacement(offset, c.span)?;
+                    self.check_bounds(offset, c.span);
+                    let cell_address = self.cell_address();
+                    let mut cell_value =
+                        self.builder
+                            .ins()
+                            .load(cell_type, mem_flags, cell_address, offset);
+                    if cell_type != I64 {
+                        cell_value = self.builder.ins().uextend(I64, cell_value);
+                    }
+
+                    let inst = self.builder.ins().call_indirect(
+                        self.write_sig,
+                        self.write_address,
+                        &[self.context, cell_value],
+                    );
+                    self.exit_on_error(inst);
+                }
+                NodeKind::Read { offset } => {
+                    let offset = cell.displacement(offset, c.span)?;
+                    self.check_bounds(offset, c.span);
+                    let cell_address = self.cell_address();
+                    let cell_address = self.builder.ins().iadd_imm(cell_address, offset as i64);
+                    self.builder
                         .ins()
-                        .brif(cell_value, inner_block, &[], after_block, &[]);
-                    builder.switch_to_block(inner_block);
-
-                    loop_stack.push((inner_block, after_block));
+                        .load(cell_type, mem_flags, cell_address, 0);
+
+                    let inst = self.builder.ins().call_indirect(
+                        self.read_sig,
+                        self.read_address,
+                        &[self.context, cell_address],
+                    );
+                    self.exit_on_error(inst);
                 }
-                Node::LoopEnd => {
-                    let (inner_block, after_block) = match loop_stack.pop() {
-                        Some(x) => x,
-                        None => return Err("Unclosing loop found.".to_string()),
-                    };
-
-                    let pointer_value = builder.use_var(pointer);
-                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
-                    let cell_value = builder.ins().load(I8, mem_flags, cell_address, 0);
-
-                    builder
+                NodeKind::Output(ref values) => {
+                    let pointer_type = self.builder.func.dfg.value_type(self.context);
+                    let start = self
+                        .builder
+                        .ins()
+                        .iconst(pointer_type, self.data.len() as i64);
+                    let len = self.builder.ins().iconst(pointer_type, values.len() as i64);
+                    self.data.extend_from_slice(values);
+
+                    let inst = self.builder.ins().call_indirect(
+                        self.write_data_sig,
+                        self.write_data_address,
+                        &[self.context, start, len],
+                    );
+                    self.exit_on_error(inst);
+                }
+                NodeKind::Loop(ref body) => {
+                    let inner_block = self.builder.create_block();
+                    let after_block = self.builder.create_block();
+
+                    self.check_bounds(0, c.span);
+                    let cell_address = self.cell_address();
+                    let cell_value = self
+                        .builder
+                        .ins()
+                        .load(cell_type, mem_flags, cell_address, 0);
+                    self.builder
                         .ins()
                         .brif(cell_value, inner_block, &[], after_block, &[]);
+                    self.builder.switch_to_block(inner_block);
+
+                    self.translate(body)?;
+
+                    // the body of a conditional loop leaves the cell zero
+                    if analysis::classify(body) == LoopClass::Conditional {
+                        self.builder.ins().jump(after_block, &[]);
+                    } else {
+                        self.check_interrupts(body.len() + 1);
+                        self.check_bounds(0, c.span);
+                        let cell_address = self.cell_address();
+                        let cell_value =
+                            self.builder
+                                .ins()
+                                .load(cell_type, mem_flags, cell_address, 0);
+                        self.builder
+                            .ins()
+                            .brif(cell_value, inner_block, &[], after_block, &[]);
+                    }
+                    let builder = &mut self.builder;
 
                     builder.seal_block(inner_block);
                     builder.seal_block(after_block);
@@ -177,60 +513,148 @@ impl Program {
                 }
             }
         }
+        Ok(())
+    }
 
-        if !loop_stack.is_empty() {
-            return Err("Unclosing loop found.".to_string());
+    /// Exits with an out-of-bounds error for `span` unless the cell at
+    /// `offset` from the pointer lies inside the tape.
+    fn check_bounds(&mut self, offset: i32, span: Span) {
+        if !self.checked {
+            return;
         }
+        let pointer_value = self.builder.use_var(self.pointer);
+        let cell = self.builder.ins().iadd_imm(pointer_value, offset as i64);
+        let outside =
+            self.builder
+                .ins()
+                .icmp(IntCC::UnsignedGreaterThanOrEqual, cell, self.tape_len);
+        self.exit_if(outside, self.bounds_address, span);
+    }
 
-        builder.ins().return_(&[zero]);
-
-        builder.switch_to_block(exit_block);
-        builder.seal_block(exit_block);
-
-        let result = builder.block_params(exit_block)[0];
-        builder.ins().return_(&[result]);
-
-        builder.finalize();
-
-        let res = verify_function(&func, &*isa);
-
-        if let Err(errors) = res {
-            panic!("{}", errors);
-        }
+    /// Charges `cost` steps to the fuel in the runtime context and polls its
+    /// cancellation flag, leaving through the limit block once either runs out.
+    fn check_interrupts(&mut self, cost: usize) {
+        let Some(limit_block) = self.limit_block else {
+            return;
+        };
+        let pointer_type = self.builder.func.dfg.value_type(self.context);
+        let flags = MemFlags::trusted();
+        let fuel_offset = std::mem::offset_of!(runtime::Context, budget.fuel) as i32;
+        let cancelled_offset = std::mem::offset_of!(runtime::Context, budget.cancelled) as i32;
+
+        let fuel = self
+            .builder
+            .ins()
+            .load(I64, flags, self.context, fuel_offset);
+        let cost = self.builder.ins().iconst(I64, cost as i64);
+        let (fuel, out_of_fuel) = self.builder.ins().usub_overflow(fuel, cost);
+        self.builder
+            .ins()
+            .store(flags, fuel, self.context, fuel_offset);
+        let cancelled =
+            self.builder
+                .ins()
+                .load(pointer_type, flags, self.context, cancelled_offset);
+        let cancelled = self.builder.ins().load(types::I8, flags, cancelled, 0);
+        let stop = self.builder.ins().bor(out_of_fuel, cancelled);
+
+        let after_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(stop, limit_block, &[], after_block, &[]);
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+    }
 
-        let mut ctx = Context::for_function(func);
-        let mut control_plane = ControlPlane::default();
-        let compiled = match ctx.compile(&*isa, &mut control_plane) {
-            Ok(x) => x,
-            Err(err) => {
-                eprintln!("error compiling: {:?}", err);
-                std::process::exit(8);
+    /// Adds `n` times `counter`, or just `n` without a counter, to
+    /// `cell_value`, handling a sum outside the cell range as `self.overflow`
+    /// says. A counter is at least one.
+    fn add_checked(
+        &mut self,
+        cell_value: Value,
+        counter: Option<Value>,
+        n: i64,
+        span: Span,
+    ) -> Value {
+        let cell_type = self.cell_type;
+        let magnitude = n.unsigned_abs();
+        let (sum, overflow) = if magnitude > self.cell.max() {
+            (cell_value, self.builder.ins().iconst(types::I8, 1))
+        } else {
+            let amount = self.builder.ins().iconst(cell_type, magnitude as i64);
+            let (amount, product_overflow) = match counter {
+                Some(counter) if magnitude != 1 => {
+                    let (product, overflow) = self.builder.ins().umul_overflow(counter, amount);
+                    (product, Some(overflow))
+                }
+                Some(counter) => (counter, None),
+                None => (amount, None),
+            };
+            let (sum, overflow) = if n > 0 {
+                self.builder.ins().uadd_overflow(cell_value, amount)
+            } else {
+                self.builder.ins().usub_overflow(cell_value, amount)
+            };
+            match product_overflow {
+                Some(product_overflow) => (sum, self.builder.ins().bor(product_overflow, overflow)),
+                None => (sum, overflow),
             }
         };
-        let bytes = compiled.code_buffer().to_vec();
-        Ok(Program { bytes })
+        match self.overflow {
+            Overflow::Saturate => {
+                let limit = if n > 0 { self.cell.max() as i64 } else { 0 };
+                let limit = self.builder.ins().iconst(cell_type, limit);
+                self.builder.ins().select(overflow, limit, sum)
+            }
+            _ => {
+                self.exit_if(overflow, self.overflow_address, span);
+                sum
+            }
+        }
     }
 
-    pub fn run(&mut self) -> Result<(), String> {
-        let mut memory = [0; INIT_MEMORY_SIZE];
+    /// Exits with the error created by the helper at `report` for `span` if
+    /// `condition` is non-zero.
+    fn exit_if(&mut self, condition: Value, report: Value, span: Span) {
+        let pointer_type = self.builder.func.dfg.value_type(self.context);
+        let error_block = self.builder.create_block();
+        let after_block = self.builder.create_block();
+        self.builder.set_cold_block(error_block);
+        self.builder
+            .ins()
+            .brif(condition, error_block, &[], after_block, &[]);
+
+        self.builder.seal_block(error_block);
+        self.builder.switch_to_block(error_block);
+        let args = [span.offset, span.line, span.column]
+            .map(|value| self.builder.ins().iconst(pointer_type, value as i64));
+        let inst = self
+            .builder
+            .ins()
+            .call_indirect(self.report_sig, report, &args);
+        let error = self.builder.inst_results(inst)[0];
+        self.builder.ins().jump(self.exit_block, &[error]);
+
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+    }
 
-        let mut buffer = memmap2::MmapOptions::new()
-            .len(self.bytes.len())
-            .map_anon()
-            .unwrap();
-        buffer.copy_from_slice(&self.bytes);
+    /// Address of the cell under the data pointer.
+    fn cell_address(&mut self) -> Value {
+        let pointer_value = self.builder.use_var(self.pointer);
+        self.builder.ins().iadd(self.memory_address, pointer_value)
+    }
 
-        let buffer = buffer.make_exec().unwrap();
-        unsafe {
-            let func: unsafe extern "sysv64" fn(*mut u8) -> *mut std::io::Error =
-                std::mem::transmute(buffer.as_ptr());
-            let error = func(memory.as_mut_ptr());
+    /// Returns from the function if the helper called by `inst` failed.
+    fn exit_on_error(&mut self, inst: Inst) {
+        let result = self.builder.inst_results(inst)[0];
+        let after_block = self.builder.create_block();
 
-            if !error.is_null() {
-                return Err((*Box::from_raw(error)).to_string());
-            }
-        }
+        self.builder
+            .ins()
+            .brif(result, self.exit_block, &[result], after_block, &[]);
 
-        Ok(())
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
     }
 }
@@ -0,0 +1,24 @@
+//! Disassembly of the machine code generated by the JIT backends.
+
+use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+use std::fmt::Write;
+
+/// Disassembles x86-64 `bytes` into one line per instruction, showing its
+/// offset, encoding and Intel syntax.
+pub fn disassemble(bytes: &[u8]) -> String {
+    let mut decoder = Decoder::with_ip(64, bytes, 0, DecoderOptions::NONE);
+    let mut formatter = IntelFormatter::new();
+    let mut out = String::new();
+    let mut text = String::new();
+    for instruction in &mut decoder {
+        text.clear();
+        formatter.format(&instruction, &mut text);
+        let start = instruction.ip() as usize;
+        let encoding: String = bytes[start..start + instruction.len()]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let _ = writeln!(out, "{:08x}  {:<24} {}", start, encoding, text);
+    }
+    out
+}
@@ -0,0 +1,72 @@
+use crate::limits::Limit;
+use crate::parser::Span;
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum BfError {
+    /// Malformed source, such as an unmatched bracket.
+    Syntax { span: Span, message: String },
+    /// Code generation or JIT compilation failed.
+    Compile(String),
+    /// Reading input or writing output failed.
+    Io(io::Error),
+    /// Memory for the tape or for generated code could not be mapped.
+    Memory(io::Error),
+    /// The data pointer moved outside the tape.
+    TapeOutOfBounds { span: Option<Span> },
+    /// Arithmetic took a cell out of its range with `--overflow=error`.
+    CellOverflow { span: Span },
+    /// Execution was stopped by a resource limit.
+    LimitExceeded(Limit),
+}
+
+impl BfError {
+    pub(crate) fn syntax(span: Span, message: impl Into<String>) -> BfError {
+        BfError::Syntax {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Source location the error refers to, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            BfError::Syntax { span, .. } => Some(*span),
+            BfError::TapeOutOfBounds { span } => *span,
+            BfError::CellOverflow { span } => Some(*span),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}: ", span)?;
+        }
+        match self {
+            BfError::Syntax { message, .. } => write!(f, "{}", message),
+            BfError::Compile(msg) => write!(f, "compile error: {}", msg),
+            BfError::Io(err) => write!(f, "I/O error: {}", err),
+            BfError::Memory(err) => write!(f, "cannot map memory: {}", err),
+            BfError::TapeOutOfBounds { .. } => write!(f, "tape pointer out of bounds"),
+            BfError::CellOverflow { .. } => write!(f, "cell overflow"),
+            BfError::LimitExceeded(limit) => write!(f, "limit exceeded: {}", limit),
+        }
+    }
+}
+
+impl std::error::Error for BfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BfError::Io(err) | BfError::Memory(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BfError {
+    fn from(err: io::Error) -> Self {
+        BfError::Io(err)
+    }
+}
@@ -1,100 +1,624 @@
-use crate::parser::Node;
-use crate::{read, write};
-use dynasmrt::{dynasm, x64::X64Relocation, DynasmApi, DynasmLabelApi, VecAssembler};
+use crate::analysis::{self, LoopClass};
+use crate::cell::{CellWidth, Overflow};
+use crate::error::BfError;
+use crate::parser::{Node, NodeKind, Span};
+use crate::runtime::{
+    cell_overflow, limit_exceeded, out_of_bounds, read, write, write_data, Context,
+};
+use dynasmrt::{dynasm, x64::X64Relocation, DynamicLabel, DynasmApi, DynasmLabelApi, VecAssembler};
 
-pub(crate) fn emit(code: &Vec<Node>) -> Result<Vec<u8>, String> {
-    let mut bytes: VecAssembler<X64Relocation> = VecAssembler::new(0);
-    let mut loop_labels = Vec::new();
+type Assembler = VecAssembler<X64Relocation>;
+
+/// Helper creating the error for a failed check at a source position.
+type Report = extern "sysv64" fn(usize, usize, usize) -> *mut BfError;
+
+/// Bounds checks of pointer moves and cell accesses in `--checked` mode,
+/// overflow checks of cell arithmetic unless cells wrap, and fuel and
+/// cancellation checks at loop back edges under a step or time limit.
+struct Checks {
+    bounds: bool,
+    overflow: Overflow,
+    interrupts: bool,
+    /// Jump targets reporting a failed check at a source position.
+    exits: Vec<(DynamicLabel, Span, Report)>,
+}
+
+/// Generates the function running `code` on cells of width `cell`, whose
+/// arithmetic overflows as `overflow` says. Constant output is appended to
+/// `data`, which must be passed to the function in its runtime context. With
+/// `checked` every pointer move and cell access is checked against the tape
+/// length, and with `interrupts` the top level and every loop iteration charge
+/// the context's fuel and poll its cancellation flag.
+pub(crate) fn emit(
+    code: &[Node],
+    data: &mut Vec<u8>,
+    cell: CellWidth,
+    overflow: Overflow,
+    checked: bool,
+    interrupts: bool,
+) -> Result<Vec<u8>, BfError> {
+    let mut bytes: Assembler = VecAssembler::new(0);
 
     // r12 will be the address of `memory`
-    // r13 will be the value of `pointer`
+    // r13 will be the value of `pointer`, in bytes
+    // r14 will be the runtime context passed to helpers
+    // r15 will be the length of `memory`, in bytes
     // r12 is got from argument 1 in `rdi`
-    // r13 is set to 0
+    // r13 is got from argument 4 in `rcx`
+    // r14 is got from argument 2 in `rsi`
+    // r15 is got from argument 3 in `rdx`
     dynasm! { bytes
         ; .arch x64
         ; push rbp
         ; mov rbp, rsp
         ; push r12
         ; push r13
+        ; push r14
+        ; push r15
         ; mov r12, rdi
-        ; xor r13, r13
+        ; mov r13, rcx
+        ; mov r14, rsi
+        ; mov r15, rdx
     };
 
-    for op in code {
-        match op {
-            Node::Increment(n) => dynasm! { bytes
-                ; .arch x64
-                ; add BYTE [r12 + r13], *n as i8
-            },
-            Node::Decrement(n) => dynasm! { bytes
-                ; .arch x64
-                ; sub BYTE [r12 + r13], *n as i8
-            },
-            Node::Next(n) => dynasm! { bytes
+    let mut checks = Checks {
+        bounds: checked,
+        overflow,
+        interrupts,
+        exits: Vec::new(),
+    };
+    check_interrupts(&mut bytes, &checks, code.len());
+    emit_nodes(&mut bytes, code, data, cell, &mut checks)?;
+
+    dynasm! { bytes
+        ; .arch x64
+        ; xor rax, rax
+        ; ->exit:
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbp
+        ; ret
+    }
+
+    if interrupts {
+        dynasm! { bytes
+            ; .arch x64
+            ; ->limit:
+            ; mov rdi, r14
+            ; mov rax, QWORD limit_exceeded as *const() as i64
+            ; call rax
+            ; jmp ->exit
+        }
+    }
+
+    for (label, span, report) in checks.exits {
+        dynasm! { bytes
+            ; .arch x64
+            ; =>label
+            ; mov rdi, QWORD span.offset as i64
+            ; mov rsi, QWORD span.line as i64
+            ; mov rdx, QWORD span.column as i64
+            ; mov rax, QWORD report as *const() as i64
+            ; call rax
+            ; jmp ->exit
+        }
+    }
+
+    bytes
+        .finalize()
+        .map_err(|e| BfError::Compile(e.to_string()))
+}
+
+fn emit_nodes(
+    bytes: &mut Assembler,
+    code: &[Node],
+    data: &mut Vec<u8>,
+    cell: CellWidth,
+    checks: &mut Checks,
+) -> Result<(), BfError> {
+    let is_mul_add = |i: usize| {
+        matches!(
+            code.get(i).map(|node| &node.kind),
+            Some(NodeKind::MulAdd { .. })
+        )
+    };
+    let mut mul_skip_label = None;
+
+    for (i, op) in code.iter().enumerate() {
+        match op.kind {
+            NodeKind::Add { offset, value } => {
+                let offset = cell.displacement(offset, op.span)?;
+                check_bounds(bytes, checks, offset, op.span);
+                if checks.overflow == Overflow::Wrap {
+                    add_to_cell(bytes, cell, offset, value);
+                } else {
+                    add_to_cell_checked(bytes, checks, cell, offset, value, op.span);
+                }
+            }
+            NodeKind::Set { offset, value } => {
+                let offset = cell.displacement(offset, op.span)?;
+                check_bounds(bytes, checks, offset, op.span);
+                set_cell(bytes, cell, offset, value);
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                let offset = cell.displacement(offset, op.span)?;
+                if i == 0 || !is_mul_add(i - 1) {
+                    let skip_label = bytes.new_dynamic_label();
+                    check_bounds(bytes, checks, 0, op.span);
+                    compare_cell_with_zero(bytes, cell, 0);
+                    dynasm! { bytes
+                        ; .arch x64
+                        ; je =>skip_label
+                    };
+                    mul_skip_label = Some(skip_label);
+                }
+                check_bounds(bytes, checks, offset, op.span);
+                load_cell(bytes, cell, 0);
+                if checks.overflow != Overflow::Wrap {
+                    mul_add_checked(bytes, checks, cell, offset, factor, op.span);
+                } else {
+                    if factor != 1 {
+                        match i32::try_from(factor) {
+                            Ok(factor) => dynasm! { bytes
+                                ; .arch x64
+                                ; imul rax, rax, factor
+                            },
+                            // only the low bits of the product matter for narrower cells
+                            Err(_) => dynasm! { bytes
+                                ; .arch x64
+                                ; mov rcx, QWORD factor
+                                ; imul rax, rcx
+                            },
+                        }
+                    }
+                    add_rax_to_cell(bytes, cell, offset);
+                }
+                if !is_mul_add(i + 1) {
+                    if let Some(skip_label) = mul_skip_label.take() {
+                        dynasm! { bytes
+                            ; .arch x64
+                            ; =>skip_label
+                        };
+                    }
+                }
+            }
+            NodeKind::Scan(stride) => {
+                let stride = cell.displacement(stride, op.span)?;
+                dynasm! { bytes
+                    ; .arch x64
+                    ; jmp >check
+                    ; next:
+                    ; add r13, stride
+                    ; check:
+                };
+                check_bounds(bytes, checks, 0, op.span);
+                compare_cell_with_zero(bytes, cell, 0);
+                dynasm! { bytes
+                    ; .arch x64
+                    ; jne <next
+                };
+            }
+            NodeKind::Move(n) => {
+                let n = cell.move_bytes(n, op.span)?;
+                match i32::try_from(n) {
+                    Ok(n) => dynasm! { bytes
+                        ; .arch x64
+                        ; add r13, n
+                    },
+                    Err(_) => dynasm! { bytes
+                        ; .arch x64
+                        ; mov rax, QWORD n
+                        ; add r13, rax
+                    },
+                }
+                check_bounds(bytes, checks, 0, op.span);
+            }
+            NodeKind::Write { offset } => {
+                let offset = cell.displacement(offset, op.span)?;
+                check_bounds(bytes, checks, offset, op.span);
+                load_cell(bytes, cell, offset);
+                dynasm! { bytes
+                    ; .arch x64
+                    ; mov rsi, rax // value
+                    ; mov rax, QWORD write as *const() as i64
+                    ; mov rdi, r14
+                    ; call rax
+                    ; cmp rax, 0
+                    ; jne ->exit
+                }
+            }
+            NodeKind::Read { offset } => {
+                let offset = cell.displacement(offset, op.span)?;
+                check_bounds(bytes, checks, offset, op.span);
+                load_cell(bytes, cell, offset);
+                dynasm! { bytes
+                    ; .arch x64
+                    ; mov rax, QWORD read as *const() as i64
+                    ; mov rdi, r14
+                    ; lea rsi, [r12 + r13 + offset] // buf address
+                    ; call rax
+                    ; cmp rax, 0
+                    ; jne ->exit
+                }
+            }
+            NodeKind::Output(ref values) => {
+                let start = data.len();
+                data.extend_from_slice(values);
+                dynasm! { bytes
+                    ; .arch x64
+                    ; mov rax, QWORD write_data as *const() as i64
+                    ; mov rdi, r14
+                    ; mov rsi, QWORD start as i64
+                    ; mov rdx, QWORD values.len() as i64
+                    ; call rax
+                    ; cmp rax, 0
+                    ; jne ->exit
+                }
+            }
+            NodeKind::Loop(ref body) => {
+                let start_label = bytes.new_dynamic_label();
+                let end_label = bytes.new_dynamic_label();
+
+                check_bounds(bytes, checks, 0, op.span);
+                compare_cell_with_zero(bytes, cell, 0);
+                dynasm! { bytes
+                    ; .arch x64
+                    ; je =>end_label
+                    ; => start_label
+                }
+                emit_nodes(bytes, body, data, cell, checks)?;
+                // the body of a conditional loop leaves the cell zero
+                if analysis::classify(body) != LoopClass::Conditional {
+                    check_interrupts(bytes, checks, body.len() + 1);
+                    check_bounds(bytes, checks, 0, op.span);
+                    compare_cell_with_zero(bytes, cell, 0);
+                    dynasm! { bytes
+                        ; .arch x64
+                        ; jne => start_label
+                    }
+                }
+                dynasm! { bytes
+                    ; .arch x64
+                    ; => end_label
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds `value`, truncated to the cell width, to the cell at byte `offset`
+/// from the pointer.
+fn add_to_cell(bytes: &mut Assembler, cell: CellWidth, offset: i32, value: i64) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; add BYTE [r12 + r13 + offset], value as i8
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; add WORD [r12 + r13 + offset], value as i16
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; add DWORD [r12 + r13 + offset], value as i32
+        },
+        CellWidth::Bits64 => match i32::try_from(value) {
+            Ok(value) => dynasm! { bytes
                 ; .arch x64
-                ; add r13, *n as i32
+                ; add QWORD [r12 + r13 + offset], value
             },
-            Node::Prev(n) => dynasm! { bytes
+            Err(_) => dynasm! { bytes
                 ; .arch x64
-                ; sub r13, *n as i32
+                ; mov rax, QWORD value
+                ; add QWORD [r12 + r13 + offset], rax
             },
-            Node::Write => dynasm! { bytes
+        },
+    }
+}
+
+/// Subtracts `value`, truncated to the cell width, from the cell at byte
+/// `offset` from the pointer.
+fn sub_from_cell(bytes: &mut Assembler, cell: CellWidth, offset: i32, value: i64) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; sub BYTE [r12 + r13 + offset], value as i8
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; sub WORD [r12 + r13 + offset], value as i16
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; sub DWORD [r12 + r13 + offset], value as i32
+        },
+        CellWidth::Bits64 => match i32::try_from(value) {
+            Ok(value) => dynasm! { bytes
                 ; .arch x64
-                ; mov rax, QWORD write as *const() as i64
-                ; mov rdi, [r12 + r13] // buf address
-                ; call rax
-                ; cmp rax, 0
-                ; jne ->exit
+                ; sub QWORD [r12 + r13 + offset], value
             },
-            Node::Read => dynasm! { bytes
+            Err(_) => dynasm! { bytes
                 ; .arch x64
-                ; mov rax, QWORD read as *const() as i64
-                ; lea rdi, [r12 + r13] // buf address
-                ; call rax
-                ; cmp rax, 0
-                ; jne ->exit
-                ; ret
+                ; mov rax, QWORD value
+                ; sub QWORD [r12 + r13 + offset], rax
             },
-            Node::LoopBegin => {
-                let start_label = bytes.new_dynamic_label();
-                let end_label = bytes.new_dynamic_label();
+        },
+    }
+}
 
-                dynasm! { bytes
-                    ; .arch x64
-                    ; cmp BYTE [r12 + r13], 0
-                    ; je =>end_label
-                    ; => start_label
-                }
-                loop_labels.push((start_label, end_label));
+/// Adds `value` to the cell at byte `offset` from the pointer, handling a sum
+/// outside the cell range as `checks.overflow` says. The carry flag of the
+/// addition or subtraction at the cell width tells whether it overflowed.
+fn add_to_cell_checked(
+    bytes: &mut Assembler,
+    checks: &mut Checks,
+    cell: CellWidth,
+    offset: i32,
+    value: i64,
+    span: Span,
+) {
+    let overflow = overflow_label(bytes, checks, span);
+    let magnitude = value.unsigned_abs();
+    if magnitude > cell.max() {
+        dynasm! { bytes
+            ; .arch x64
+            ; jmp =>overflow
+        }
+    } else {
+        if value > 0 {
+            add_to_cell(bytes, cell, offset, magnitude as i64);
+        } else {
+            sub_from_cell(bytes, cell, offset, magnitude as i64);
+        }
+        dynasm! { bytes
+            ; .arch x64
+            ; jc =>overflow
+        }
+    }
+    saturate(bytes, checks, cell, offset, overflow, value > 0);
+}
+
+/// Adds `rax` times `factor` to the cell at byte `offset` from the pointer,
+/// where `rax` holds a counter of at least one, handling a sum outside the
+/// cell range as `checks.overflow` says.
+fn mul_add_checked(
+    bytes: &mut Assembler,
+    checks: &mut Checks,
+    cell: CellWidth,
+    offset: i32,
+    factor: i64,
+    span: Span,
+) {
+    let overflow = overflow_label(bytes, checks, span);
+    let magnitude = factor.unsigned_abs();
+    if magnitude > cell.max() {
+        dynasm! { bytes
+            ; .arch x64
+            ; jmp =>overflow
+        }
+    } else {
+        if magnitude != 1 {
+            dynasm! { bytes
+                ; .arch x64
+                ; mov rcx, QWORD magnitude as i64
+                ; mul rcx
+                ; jc =>overflow
             }
-            Node::LoopEnd => {
-                let (start_label, end_label) = match loop_labels.pop() {
-                    Some(x) => x,
-                    None => return Err("Unclosing loop found.".to_string()),
-                };
+            if cell != CellWidth::Bits64 {
+                // the product must fit in the cell too
                 dynasm! { bytes
                     ; .arch x64
-                    ; cmp BYTE [r12 + r13], 0
-                    ; jne => start_label
-                    ; => end_label
+                    ; mov rcx, rax
+                    ; shr rcx, cell.bits() as i8
+                    ; jnz =>overflow
                 }
             }
         }
+        if factor > 0 {
+            add_rax_to_cell(bytes, cell, offset);
+        } else {
+            sub_rax_from_cell(bytes, cell, offset);
+        }
+        dynasm! { bytes
+            ; .arch x64
+            ; jc =>overflow
+        }
     }
+    saturate(bytes, checks, cell, offset, overflow, factor > 0);
+}
 
-    if !loop_labels.is_empty() {
-        return Err("Unclosing loop found.".to_string());
+/// Charges `cost` steps to the fuel in the context and polls its cancellation
+/// flag, leaving through `->limit` once either runs out.
+fn check_interrupts(bytes: &mut Assembler, checks: &Checks, cost: usize) {
+    if !checks.interrupts {
+        return;
     }
+    let fuel = std::mem::offset_of!(Context, budget.fuel) as i32;
+    let cancelled = std::mem::offset_of!(Context, budget.cancelled) as i32;
+    let cost = i32::try_from(cost).unwrap_or(i32::MAX);
+    dynasm! { bytes
+        ; .arch x64
+        ; sub QWORD [r14 + fuel], cost
+        ; jc ->limit
+        ; mov rax, QWORD [r14 + cancelled]
+        ; cmp BYTE [rax], 0
+        ; jne ->limit
+    }
+}
 
+/// Label that arithmetic overflowing a cell jumps to: an exit reporting the
+/// overflow at `span` in error mode, or the code placed by [`saturate`].
+fn overflow_label(bytes: &mut Assembler, checks: &mut Checks, span: Span) -> DynamicLabel {
+    let label = bytes.new_dynamic_label();
+    if checks.overflow == Overflow::Error {
+        checks.exits.push((label, span, cell_overflow));
+    }
+    label
+}
+
+/// In saturate mode, places the code at `label` that clamps the cell at byte
+/// `offset` from the pointer to its largest value when adding `up`, or to
+/// zero otherwise.
+fn saturate(
+    bytes: &mut Assembler,
+    checks: &Checks,
+    cell: CellWidth,
+    offset: i32,
+    label: DynamicLabel,
+    up: bool,
+) {
+    if checks.overflow != Overflow::Saturate {
+        return;
+    }
     dynasm! { bytes
         ; .arch x64
-        ; xor rax, rax
-        ; ->exit:
-        ; pop r13
-        ; pop r12
-        ; pop rbp
-        ; ret
+        ; jmp >done
+        ; =>label
+    }
+    // all bits set is the largest value of every width
+    set_cell(bytes, cell, offset, if up { -1 } else { 0 });
+    dynasm! { bytes
+        ; .arch x64
+        ; done:
     }
+}
 
-    bytes.finalize().map_err(|e| e.to_string())
+/// Stores `value`, truncated to the cell width, in the cell at byte `offset`
+/// from the pointer.
+fn set_cell(bytes: &mut Assembler, cell: CellWidth, offset: i32, value: i64) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; mov BYTE [r12 + r13 + offset], value as i8
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; mov WORD [r12 + r13 + offset], value as i16
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; mov DWORD [r12 + r13 + offset], value as i32
+        },
+        CellWidth::Bits64 => match i32::try_from(value) {
+            Ok(value) => dynasm! { bytes
+                ; .arch x64
+                ; mov QWORD [r12 + r13 + offset], value
+            },
+            Err(_) => dynasm! { bytes
+                ; .arch x64
+                ; mov rax, QWORD value
+                ; mov QWORD [r12 + r13 + offset], rax
+            },
+        },
+    }
+}
+
+/// Sets the flags by comparing the cell at byte `offset` from the pointer
+/// with zero.
+fn compare_cell_with_zero(bytes: &mut Assembler, cell: CellWidth, offset: i32) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; cmp BYTE [r12 + r13 + offset], 0
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; cmp WORD [r12 + r13 + offset], 0
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; cmp DWORD [r12 + r13 + offset], 0
+        },
+        CellWidth::Bits64 => dynasm! { bytes
+            ; .arch x64
+            ; cmp QWORD [r12 + r13 + offset], 0
+        },
+    }
+}
+
+/// Loads the cell at byte `offset` from the pointer into `rax`, zero-extended.
+fn load_cell(bytes: &mut Assembler, cell: CellWidth, offset: i32) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; movzx eax, BYTE [r12 + r13 + offset]
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; movzx eax, WORD [r12 + r13 + offset]
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; mov eax, DWORD [r12 + r13 + offset]
+        },
+        CellWidth::Bits64 => dynasm! { bytes
+            ; .arch x64
+            ; mov rax, QWORD [r12 + r13 + offset]
+        },
+    }
+}
+
+/// Adds `rax`, truncated to the cell width, to the cell at byte `offset` from
+/// the pointer.
+fn add_rax_to_cell(bytes: &mut Assembler, cell: CellWidth, offset: i32) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; add BYTE [r12 + r13 + offset], al
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; add WORD [r12 + r13 + offset], ax
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; add DWORD [r12 + r13 + offset], eax
+        },
+        CellWidth::Bits64 => dynasm! { bytes
+            ; .arch x64
+            ; add QWORD [r12 + r13 + offset], rax
+        },
+    }
+}
+
+/// Subtracts `rax`, truncated to the cell width, from the cell at byte
+/// `offset` from the pointer.
+fn sub_rax_from_cell(bytes: &mut Assembler, cell: CellWidth, offset: i32) {
+    match cell {
+        CellWidth::Bits8 => dynasm! { bytes
+            ; .arch x64
+            ; sub BYTE [r12 + r13 + offset], al
+        },
+        CellWidth::Bits16 => dynasm! { bytes
+            ; .arch x64
+            ; sub WORD [r12 + r13 + offset], ax
+        },
+        CellWidth::Bits32 => dynasm! { bytes
+            ; .arch x64
+            ; sub DWORD [r12 + r13 + offset], eax
+        },
+        CellWidth::Bits64 => dynasm! { bytes
+            ; .arch x64
+            ; sub QWORD [r12 + r13 + offset], rax
+        },
+    }
+}
+
+/// Exits with an out-of-bounds error for `span` unless the cell at byte
+/// `offset` from the pointer lies inside the tape.
+fn check_bounds(bytes: &mut Assembler, checks: &mut Checks, offset: i32, span: Span) {
+    if !checks.bounds {
+        return;
+    }
+    let label = bytes.new_dynamic_label();
+    checks.exits.push((label, span, out_of_bounds));
+    dynasm! { bytes
+        ; .arch x64
+        ; lea rax, [r13 + offset]
+        ; cmp rax, r15
+        ; jae =>label
+    }
 }
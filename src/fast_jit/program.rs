@@ -1,39 +1,70 @@
+use crate::analysis;
+use crate::backend::Backend;
+use crate::error::BfError;
 use crate::fast_jit::code_gen;
-use crate::parser::parse;
-use crate::INIT_MEMORY_SIZE;
+use crate::guard::{self, Bounds};
+use crate::io::Io;
+use crate::options::Options;
+use crate::parser::Node;
+use crate::runtime::{Context, Entry};
+use crate::tape::Tape;
 use dynasmrt::mmap::MutableBuffer;
+use std::ops::RangeInclusive;
 
 pub struct Program {
     bytes: Vec<u8>,
+    /// Output computed at compile time, referenced by the generated code.
+    data: Vec<u8>,
+    bounds: Bounds,
+    tape_range: Option<RangeInclusive<isize>>,
+    options: Options,
 }
 
-impl Program {
-    pub fn new(source: &str) -> Result<Program, String> {
-        let code = parse(source)?;
-        let bytes = code_gen::emit(&code)?;
-        Ok(Program { bytes })
+impl Backend for Program {
+    fn compile_ir(code: &[Node], options: &Options) -> Result<Program, BfError> {
+        let bounds = Bounds::of(code, options)?;
+        let mut data = Vec::new();
+        let bytes = code_gen::emit(
+            code,
+            &mut data,
+            options.cell,
+            options.overflow,
+            bounds.checked(),
+            options.limits.interrupts(),
+        )?;
+        Ok(Program {
+            bytes,
+            data,
+            bounds,
+            tape_range: analysis::tape_range(code),
+            options: *options,
+        })
     }
 
-    pub fn run(&self) -> Result<(), String> {
-        let mut memory = [0; INIT_MEMORY_SIZE];
-        let mut buffer = MutableBuffer::new(self.bytes.len()).unwrap();
-        buffer.set_len(self.bytes.len());
+    fn code(&self) -> Option<&[u8]> {
+        Some(&self.bytes)
+    }
 
-        buffer.copy_from_slice(&self.bytes);
+    fn new_tape(&self) -> Result<Tape, BfError> {
+        Tape::for_code(self.tape_range.clone(), &self.options, self.bounds.guard())
+    }
 
-        let buffer = buffer.make_exec().unwrap();
+    fn run(&mut self, tape: &mut Tape, io: &mut dyn Io) -> Result<(), BfError> {
+        let mut buffer = MutableBuffer::new(self.bytes.len()).map_err(BfError::Memory)?;
+        buffer.set_len(self.bytes.len());
 
-        unsafe {
-            let func: unsafe extern "sysv64" fn(*mut u8) -> *mut std::io::Error =
-                std::mem::transmute(buffer.as_ptr());
+        buffer.copy_from_slice(&self.bytes);
 
-            let error = func(memory.as_mut_ptr());
+        let buffer = buffer.make_exec().map_err(BfError::Memory)?;
 
-            if !error.is_null() {
-                return Err((*Box::from_raw(error)).to_string());
-            }
-        }
+        self.bounds.prepare(tape, &self.options)?;
+        let mut ctx = Context::new(io, &self.options, &self.data);
+        let result = unsafe {
+            let func: Entry = std::mem::transmute(buffer.as_ptr());
 
-        Ok(())
+            guard::call(func, tape, &mut ctx)
+        };
+        io.flush()?;
+        result
     }
 }
@@ -0,0 +1,298 @@
+//! Calls into JIT-compiled code that translate faults in the tape's guard
+//! regions into [`BfError::TapeOutOfBounds`].
+//!
+//! Generated code without bounds checks runs off the tape into one of the
+//! inaccessible regions around it. A `SIGSEGV` handler recognizes faults
+//! inside those regions and resumes execution in a trampoline that made the
+//! call, which then returns as if the generated function had failed. Faults
+//! anywhere else are passed on to the previously installed handler.
+
+use crate::analysis;
+use crate::error::BfError;
+use crate::options::Options;
+use crate::parser::Node;
+use crate::runtime::{self, Context, Entry};
+use crate::tape::{Tape, TapeMode, MAX_GUARD};
+
+/// Whether [`call`] turns faults in the guard regions into errors on this
+/// platform. Elsewhere a fault kills the process, so code is checked instead.
+const TRANSLATES_FAULTS: bool = cfg!(all(target_os = "linux", target_arch = "x86_64"));
+
+/// How generated code for a program stays on the tape.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Bounds {
+    /// The program provably stays within this many bytes from its origin.
+    Known(usize),
+    /// Accesses are checked by the generated code.
+    Checked,
+    /// Leaving the tape faults in guard regions of at least this many bytes.
+    Guarded(usize),
+}
+
+impl Bounds {
+    /// How to keep `code` on the tape, with explicit checks when requested,
+    /// when a single move could skip past the largest guard region, when
+    /// faults are not translated on this platform or when the tape has a
+    /// fixed size the program may leave.
+    /// Generated code cannot wrap around a circular tape.
+    pub(crate) fn of(code: &[Node], options: &Options) -> Result<Bounds, BfError> {
+        if options.tape == TapeMode::Circular {
+            return Err(BfError::Compile(
+                "circular tapes are only supported by the interpreter".to_string(),
+            ));
+        }
+        let bytes = options.cell.bytes();
+        let required = analysis::required_tape_len(code);
+        let fits = |len: usize| match options.tape_size {
+            Some(size) => options.tape_origin.saturating_add(len) <= size,
+            None => true,
+        };
+        if let Some(len) = required.filter(|&len| fits(len)) {
+            return Ok(Bounds::Known(len.saturating_mul(bytes)));
+        }
+        let reach = analysis::max_reach(code).saturating_mul(bytes);
+        if options.checked || options.tape_size.is_some() || reach > MAX_GUARD || !TRANSLATES_FAULTS
+        {
+            Ok(Bounds::Checked)
+        } else {
+            Ok(Bounds::Guarded(reach))
+        }
+    }
+
+    pub(crate) fn checked(self) -> bool {
+        matches!(self, Bounds::Checked)
+    }
+
+    /// Bytes of the guard regions the generated code needs around its tape.
+    pub(crate) fn guard(self) -> usize {
+        match self {
+            Bounds::Guarded(reach) => reach,
+            Bounds::Known(_) | Bounds::Checked => 0,
+        }
+    }
+
+    /// Grows `tape` or its guard regions as the generated code expects,
+    /// failing if the tape it needs exceeds the tape limit of `options`.
+    pub(crate) fn prepare(self, tape: &mut Tape, options: &Options) -> Result<(), BfError> {
+        let limits = &options.limits;
+        limits.check_tape(tape.len(), options.cell)?;
+        match self {
+            Bounds::Known(len) => {
+                let len = tape.origin().saturating_add(len);
+                limits.check_tape(len, options.cell)?;
+                tape.ensure_len(len)?
+            }
+            Bounds::Checked => {}
+            Bounds::Guarded(reach) => {
+                tape.fill_capacity();
+                tape.ensure_guard(reach)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls `entry` on `tape`, turning a fault in its guard regions into an
+/// out-of-bounds error.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub(crate) unsafe fn call(entry: Entry, tape: &mut Tape, ctx: &mut Context) -> Result<(), BfError> {
+    imp::call(entry, tape, ctx)
+}
+
+/// Calls `entry` on `tape`. Faults are not translated on this platform.
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+pub(crate) unsafe fn call(entry: Entry, tape: &mut Tape, ctx: &mut Context) -> Result<(), BfError> {
+    let error = entry(tape.as_mut_ptr(), ctx, tape.len(), tape.origin());
+    runtime::take_error(error)
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod imp {
+    use super::*;
+    use dynasmrt::{dynasm, AssemblyOffset, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+    use std::cell::Cell;
+    use std::sync::{Once, OnceLock};
+    use std::{io, mem, ptr};
+
+    /// Returned by the trampoline when a guard region was hit.
+    const FAULT: usize = 1;
+
+    /// Calls `entry(memory, ctx, len, origin)` after saving its stack pointer
+    /// to `*stack`, so that execution can be resumed at `recover`.
+    type Trampoline = unsafe extern "sysv64" fn(
+        entry: Entry,
+        memory: *mut u8,
+        ctx: *mut Context,
+        len: usize,
+        origin: usize,
+        stack: *mut usize,
+    ) -> usize;
+
+    struct Code {
+        buffer: ExecutableBuffer,
+        recover: AssemblyOffset,
+    }
+
+    /// The guarded call running on this thread.
+    #[derive(Copy, Clone)]
+    struct Guarded {
+        regions: [(usize, usize); 2],
+        recover: usize,
+        /// Address of the slot the trampoline of this call saved its stack
+        /// pointer to, which lives in the frame of [`call`] so that nested
+        /// calls each restore their own.
+        stack: usize,
+    }
+
+    thread_local! {
+        static ACTIVE: Cell<Option<Guarded>> = const { Cell::new(None) };
+    }
+
+    static CODE: OnceLock<Code> = OnceLock::new();
+    static INSTALL: Once = Once::new();
+    static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+    pub(super) unsafe fn call(
+        entry: Entry,
+        tape: &mut Tape,
+        ctx: &mut Context,
+    ) -> Result<(), BfError> {
+        INSTALL.call_once(|| install());
+        let code = code()?;
+        let trampoline: Trampoline = mem::transmute(code.buffer.ptr(AssemblyOffset(0)));
+        let [below, above] = tape.guard_regions();
+        let mut saved = 0usize;
+        let stack: *mut usize = &mut saved;
+        let previous = ACTIVE.replace(Some(Guarded {
+            regions: [(below.start, below.end), (above.start, above.end)],
+            recover: code.buffer.ptr(code.recover) as usize,
+            stack: stack as usize,
+        }));
+        let result = trampoline(
+            entry,
+            tape.as_mut_ptr(),
+            ctx,
+            tape.len(),
+            tape.origin(),
+            stack,
+        );
+        ACTIVE.set(previous);
+        if result == FAULT {
+            return Err(BfError::TapeOutOfBounds { span: None });
+        }
+        runtime::take_error(result as *mut BfError)
+    }
+
+    /// The trampoline, assembled by the first call that needs it.
+    fn code() -> Result<&'static Code, BfError> {
+        if let Some(code) = CODE.get() {
+            return Ok(code);
+        }
+        let code = assemble()?;
+        Ok(CODE.get_or_init(|| code))
+    }
+
+    fn assemble() -> Result<Code, BfError> {
+        let mut ops = dynasmrt::x64::Assembler::new().map_err(BfError::Memory)?;
+        let recover = ops.new_dynamic_label();
+        // rdi: entry, rsi: memory, rdx: ctx, rcx: len, r8: origin, r9: stack
+        dynasm! { ops
+            ; .arch x64
+            ; push rbx
+            ; push rbp
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
+            ; sub rsp, 8
+            ; mov [r9], rsp
+            ; mov rax, rdi
+            ; mov rdi, rsi
+            ; mov rsi, rdx
+            ; mov rdx, rcx
+            ; mov rcx, r8
+            ; call rax
+            ; ->done:
+            ; add rsp, 8
+            ; pop r15
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbp
+            ; pop rbx
+            ; ret
+            ; =>recover
+            ; mov rax, FAULT as i32
+            ; jmp ->done
+        }
+        let recover = ops.labels().resolve_dynamic(recover).unwrap();
+        let buffer = ops.finalize().map_err(|_| {
+            BfError::Memory(io::Error::other("cannot make the trampoline executable"))
+        })?;
+        Ok(Code { buffer, recover })
+    }
+
+    unsafe fn install() {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = mem::zeroed();
+        if libc::sigaction(libc::SIGSEGV, &action, &mut previous) == 0 {
+            let _ = PREVIOUS.set(previous);
+        }
+    }
+
+    unsafe extern "C" fn handle(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        let address = (*info).si_addr() as usize;
+        if let Some(guarded) = ACTIVE.get() {
+            let hit = guarded
+                .regions
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&address));
+            if hit {
+                let context = &mut *(context as *mut libc::ucontext_t);
+                let registers = &mut context.uc_mcontext.gregs;
+                registers[libc::REG_RIP as usize] = guarded.recover as i64;
+                registers[libc::REG_RSP as usize] = *(guarded.stack as *const usize) as i64;
+                return;
+            }
+        }
+        forward(signal, info, context);
+    }
+
+    /// Hands a fault outside the guard regions to the previous handler, or
+    /// restores the default action so that the fault repeats and kills the
+    /// process on return.
+    unsafe fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+        let previous = PREVIOUS.get();
+        match previous {
+            Some(previous)
+                if previous.sa_sigaction != libc::SIG_DFL
+                    && previous.sa_sigaction != libc::SIG_IGN =>
+            {
+                if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler: unsafe extern "C" fn(
+                        libc::c_int,
+                        *mut libc::siginfo_t,
+                        *mut libc::c_void,
+                    ) = mem::transmute(previous.sa_sigaction);
+                    handler(signal, info, context);
+                } else {
+                    let handler: unsafe extern "C" fn(libc::c_int) =
+                        mem::transmute(previous.sa_sigaction);
+                    handler(signal);
+                }
+            }
+            _ => {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(signal, &action, ptr::null_mut());
+            }
+        }
+    }
+}
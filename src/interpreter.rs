@@ -1,117 +1,193 @@
-use crate::parser::{parse, Node};
-use crate::INIT_MEMORY_SIZE;
-use std::io::{Read, Write};
-use std::{cmp, io};
+use crate::analysis::{self, LoopClass};
+use crate::backend::Backend;
+use crate::cell::{self, Cell, CellWidth, Overflow};
+use crate::error::BfError;
+use crate::io::Io;
+use crate::limits::Budget;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind, Span};
+use crate::tape::{Tape, TapeMode};
+use std::cmp;
+use std::ops::RangeInclusive;
 
+/// Instructions of the interpreter. Constants are truncated to the cell
+/// width when executed.
 pub enum OpCode {
-    Increment(u8),
-    Decrement(u8),
-    Next(usize),
-    Prev(usize),
-    Write,
-    Read,
+    Add {
+        offset: isize,
+        value: u64,
+    },
+    Move(isize),
+    Set {
+        offset: isize,
+        value: u64,
+    },
+    MulAdd {
+        offset: isize,
+        factor: u64,
+    },
+    Scan(isize),
+    Write {
+        offset: isize,
+    },
+    Read {
+        offset: isize,
+    },
+    /// Writes `len` bytes of the constant data starting at `start`.
+    Output {
+        start: usize,
+        len: usize,
+    },
     LoopBegin(usize),
     LoopEnd(usize),
 }
 
-pub(crate) struct Interpreter {
+pub struct Interpreter {
     program: Vec<OpCode>,
-    memory: Vec<u8>,
+    spans: Vec<Span>,
+    /// Output computed at compile time.
+    data: Vec<u8>,
+    tape_range: Option<RangeInclusive<isize>>,
+    options: Options,
     pc: usize,
     dp: usize,
 }
 
 impl Interpreter {
-    pub fn new() -> Self {
-        Interpreter {
-            program: Vec::new(),
-            memory: vec![0u8; INIT_MEMORY_SIZE],
-            pc: 0,
-            dp: 0,
-        }
-    }
-
-    fn compile(nodes: &Vec<Node>) -> Result<Vec<OpCode>, String> {
-        let mut loop_idx = Vec::new();
-        let mut result = Vec::new();
-        for i in 0..nodes.len() {
-            let cur = &nodes[i];
-            match cur {
-                Node::Increment(n) => result.push(OpCode::Increment(*n)),
-                Node::Decrement(n) => result.push(OpCode::Decrement(*n)),
-                Node::Prev(n) => result.push(OpCode::Prev(*n)),
-                Node::Next(n) => result.push(OpCode::Next(*n)),
-                Node::Write => result.push(OpCode::Write),
-                Node::Read => result.push(OpCode::Read),
-                Node::LoopBegin => {
-                    loop_idx.push(i);
-                    result.push(OpCode::LoopBegin(0));
+    /// Flattens `nodes` into `program`, resolving loop jump targets and
+    /// collecting constant output in `data`. Loops keep their back edge on a
+    /// circular tape, where loop classification does not hold.
+    fn lower(
+        nodes: &[Node],
+        tape: TapeMode,
+        program: &mut Vec<OpCode>,
+        spans: &mut Vec<Span>,
+        data: &mut Vec<u8>,
+    ) {
+        for node in nodes {
+            let op = match node.kind {
+                NodeKind::Add { offset, value } => OpCode::Add {
+                    offset,
+                    value: value as u64,
+                },
+                NodeKind::Move(n) => OpCode::Move(n),
+                NodeKind::Scan(n) => OpCode::Scan(n),
+                NodeKind::Set { offset, value } => OpCode::Set {
+                    offset,
+                    value: value as u64,
+                },
+                NodeKind::MulAdd { offset, factor } => OpCode::MulAdd {
+                    offset,
+                    factor: factor as u64,
+                },
+                NodeKind::Write { offset } => OpCode::Write { offset },
+                NodeKind::Read { offset } => OpCode::Read { offset },
+                NodeKind::Output(ref bytes) => {
+                    let start = data.len();
+                    data.extend_from_slice(bytes);
+                    OpCode::Output {
+                        start,
+                        len: bytes.len(),
+                    }
                 }
-                Node::LoopEnd => {
-                    let idx = match loop_idx.pop() {
-                        Some(idx) => idx,
-                        None => return Err("Unclosing loop found.".to_string()),
-                    };
-                    result[idx] = OpCode::LoopBegin(i);
-                    result.push(OpCode::LoopEnd(idx));
+                NodeKind::Loop(ref body) => {
+                    let begin = program.len();
+                    program.push(OpCode::LoopBegin(0));
+                    spans.push(node.span);
+                    Self::lower(body, tape, program, spans, data);
+                    if tape != TapeMode::Circular
+                        && analysis::classify(body) == LoopClass::Conditional
+                    {
+                        // the body leaves the cell zero, so there is no back edge
+                        program[begin] = OpCode::LoopBegin(program.len() - 1);
+                        continue;
+                    }
+                    let end = program.len();
+                    program[begin] = OpCode::LoopBegin(end);
+                    OpCode::LoopEnd(begin)
                 }
-            }
-        }
-        if !loop_idx.is_empty() {
-            return Err("Unclosing loop found.".to_string());
+            };
+            program.push(op);
+            spans.push(node.span);
         }
-        Ok(result)
     }
 
-    pub fn run(&mut self, source: &str) -> Result<(), String> {
-        let nodes = parse(source)?;
-        self.program = Self::compile(&nodes)?;
-        let mut stdin = io::stdin();
-        let mut stdout = io::stdout();
+    /// Runs the program on a tape of `T` cells, taking a step of `budget`
+    /// for every instruction under a step or time limit.
+    fn execute<T: Cell>(
+        &mut self,
+        tape: &mut Tape,
+        io: &mut dyn Io,
+        budget: &mut Budget,
+    ) -> Result<(), BfError> {
+        let interrupts = self.options.limits.interrupts();
         loop {
             if self.pc >= self.program.len() {
                 break;
             }
-
-            if self.dp >= self.memory.len() {
-                let new_len = cmp::max(self.memory.len() * 2, self.dp);
-                self.memory.resize(new_len, 0);
+            if interrupts {
+                budget.step()?;
             }
 
             match self.program[self.pc] {
-                OpCode::Increment(n) => self.memory[self.dp] = self.memory[self.dp].wrapping_add(n),
-                OpCode::Decrement(n) => self.memory[self.dp] = self.memory[self.dp].wrapping_sub(n),
-                OpCode::Prev(n) => {
-                    if self.dp < n {
-                        eprintln!("Memory out of bounds.");
+                OpCode::Add { offset, value } => {
+                    let cell = self.cell::<T>(tape, offset)?;
+                    let cells = tape.cells_of_mut::<T>();
+                    cells[cell] = if self.options.overflow == Overflow::Wrap {
+                        cells[cell].wrapping_add(T::from_u64(value))
+                    } else {
+                        self.add(cells[cell], value as i64 as i128)?
+                    }
+                }
+                OpCode::Set { offset, value } => {
+                    let cell = self.cell::<T>(tape, offset)?;
+                    tape.cells_of_mut::<T>()[cell] = T::from_u64(value)
+                }
+                OpCode::MulAdd { offset, factor } => {
+                    let value = tape.cells_of::<T>()[self.dp];
+                    if value != T::ZERO {
+                        let target = self.cell::<T>(tape, offset)?;
+                        let cells = tape.cells_of_mut::<T>();
+                        cells[target] = if self.options.overflow == Overflow::Wrap {
+                            let product = value.wrapping_mul(T::from_u64(factor));
+                            cells[target].wrapping_add(product)
+                        } else {
+                            let product = value.to_u64() as i128 * factor as i64 as i128;
+                            self.add(cells[target], product)?
+                        };
                     }
-                    self.dp -= n
                 }
-                OpCode::Next(n) => self.dp += n,
-                OpCode::Read => {
-                    let mut buf = [0u8; 1];
-                    if let Err(err) = stdin.read_exact(&mut buf) {
-                        if err.kind() != io::ErrorKind::UnexpectedEof {
-                            eprintln!("Error reading: {}.", err);
-                            break;
+                OpCode::Move(n) => self.dp = self.cell::<T>(tape, n)?,
+                OpCode::Scan(stride) => {
+                    let cells = tape.cells_of::<T>();
+                    if cells[self.dp] != T::ZERO {
+                        match T::find_zero(cells, self.dp, stride) {
+                            Some(dp) => self.dp = dp,
+                            None => self.scan_past_end::<T>(tape, stride, budget)?,
                         }
-
-                        buf[0] = b'\n';
                     }
                 }
-                OpCode::Write => {
-                    if let Err(msg) = stdout.write_all(&[self.memory[self.dp]]) {
-                        eprintln!("Error writing: {}.", msg);
-                        break;
+                OpCode::Read { offset } => {
+                    let cell = self.cell::<T>(tape, offset)?;
+                    if let Some(value) = self.options.eof.resolve(io.read()?)? {
+                        tape.cells_of_mut::<T>()[cell] = T::from_u64(value);
                     }
                 }
+                OpCode::Write { offset } => {
+                    let cell = self.cell::<T>(tape, offset)?;
+                    let value = tape.cells_of::<T>()[cell].to_u64();
+                    budget.write(io, cell::encode(value, self.options.utf8, &mut [0; 4]))?;
+                }
+                OpCode::Output { start, len } => {
+                    budget.write(io, &self.data[start..start + len])?
+                }
                 OpCode::LoopBegin(idx) => {
-                    if self.memory[self.dp] == 0 {
+                    if tape.cells_of::<T>()[self.dp] == T::ZERO {
                         self.pc = idx;
                     }
                 }
                 OpCode::LoopEnd(idx) => {
-                    if self.memory[self.dp] != 0 {
+                    if tape.cells_of::<T>()[self.dp] != T::ZERO {
                         self.pc = idx;
                     }
                 }
@@ -120,13 +196,133 @@ impl Interpreter {
             self.pc += 1
         }
 
-        self.reset();
         Ok(())
     }
 
-    fn reset(&mut self) {
-        self.memory = vec![0u8; INIT_MEMORY_SIZE];
+    /// Index of the cell at `offset` from the data pointer. The tape is grown
+    /// when the cell lies past an end it extends beyond, which moves the data
+    /// pointer along when cells are added in front.
+    #[inline]
+    fn cell<T: Cell>(&mut self, tape: &mut Tape, offset: isize) -> Result<usize, BfError> {
+        match self.dp.checked_add_signed(offset) {
+            Some(index) if index < tape.cells_of::<T>().len() => Ok(index),
+            _ => self.cell_outside::<T>(tape, offset),
+        }
+    }
+
+    /// [`Interpreter::cell`] for a cell outside the tape.
+    #[cold]
+    fn cell_outside<T: Cell>(&mut self, tape: &mut Tape, offset: isize) -> Result<usize, BfError> {
+        let len = tape.cells_of::<T>().len();
+        match self.dp.checked_add_signed(offset) {
+            _ if self.options.tape == TapeMode::Circular => {
+                let index = (self.dp as i128 + offset as i128).rem_euclid(len as i128);
+                Ok(index as usize)
+            }
+            _ if self.options.tape_size.is_some() => Err(self.out_of_bounds()),
+            Some(index) => {
+                let size = size_of::<T>();
+                let limits = &self.options.limits;
+                let cell = self.options.cell;
+                tape.ensure_len(limits.grow_tape(len * 2 * size, (index + 1) * size, cell)?)?;
+                Ok(index)
+            }
+            None if offset < 0 && self.options.tape == TapeMode::Bidirectional => {
+                let size = size_of::<T>();
+                let below = offset.unsigned_abs() - self.dp;
+                let wanted = tape.len() + cmp::max(len, below) * size;
+                let needed = tape.len() + below * size;
+                let limits = &self.options.limits;
+                let cells =
+                    (limits.grow_tape(wanted, needed, self.options.cell)? - tape.len()) / size;
+                tape.grow_front(cells * size)?;
+                self.dp += cells;
+                Ok(cells - below)
+            }
+            None => Err(self.out_of_bounds()),
+        }
+    }
+
+    /// Finishes a scan by `stride` that found no zero cell before the end of
+    /// the tape. Cells past the ends are zero unless the tape is circular, in
+    /// which case the scan wraps around and goes on, taking a step of `budget`
+    /// for every cell it visits under a step or time limit.
+    fn scan_past_end<T: Cell>(
+        &mut self,
+        tape: &mut Tape,
+        stride: isize,
+        budget: &mut Budget,
+    ) -> Result<(), BfError> {
+        let interrupts = self.options.limits.interrupts();
+        let step = stride.unsigned_abs();
+        let steps = if stride > 0 {
+            (tape.cells_of::<T>().len() - self.dp).div_ceil(step)
+        } else {
+            self.dp / step + 1
+        };
+        self.dp = self.cell::<T>(tape, (steps * step) as isize * stride.signum())?;
+        while tape.cells_of::<T>()[self.dp] != T::ZERO {
+            if interrupts {
+                budget.step()?;
+            }
+            self.dp = self.cell::<T>(tape, stride)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `n` to a cell holding `value` when cells do not wrap.
+    fn add<T: Cell>(&self, value: T, n: i128) -> Result<T, BfError> {
+        let max = self.options.cell.max();
+        match self.options.overflow.add(value.to_u64(), n, max) {
+            Some(sum) => Ok(T::from_u64(sum)),
+            None => Err(BfError::CellOverflow {
+                span: self.spans[self.pc],
+            }),
+        }
+    }
+
+    fn out_of_bounds(&self) -> BfError {
+        BfError::TapeOutOfBounds {
+            span: Some(self.spans[self.pc]),
+        }
+    }
+}
+
+impl Backend for Interpreter {
+    fn compile_ir(code: &[Node], options: &Options) -> Result<Self, BfError> {
+        let mut program = Vec::new();
+        let mut spans = Vec::new();
+        let mut data = Vec::new();
+        Self::lower(code, options.tape, &mut program, &mut spans, &mut data);
+        Ok(Interpreter {
+            program,
+            spans,
+            data,
+            tape_range: analysis::tape_range(code),
+            options: *options,
+            pc: 0,
+            dp: 0,
+        })
+    }
+
+    fn new_tape(&self) -> Result<Tape, BfError> {
+        Tape::growable(self.tape_range.clone(), &self.options)
+    }
+
+    fn run(&mut self, tape: &mut Tape, io: &mut dyn Io) -> Result<(), BfError> {
+        self.options
+            .limits
+            .check_tape(tape.len(), self.options.cell)?;
         self.pc = 0;
-        self.dp = 0;
+        self.dp = tape.origin() / self.options.cell.bytes();
+        let mut budget = Budget::start(&self.options.limits);
+        let result = match self.options.cell {
+            CellWidth::Bits8 => self.execute::<u8>(tape, io, &mut budget),
+            CellWidth::Bits16 => self.execute::<u16>(tape, io, &mut budget),
+            CellWidth::Bits32 => self.execute::<u32>(tape, io, &mut budget),
+            CellWidth::Bits64 => self.execute::<u64>(tape, io, &mut budget),
+        };
+        io.flush()?;
+        result
     }
 }
@@ -0,0 +1,196 @@
+use std::io::{self, Read, StdinLock, StdoutLock, Write};
+
+/// What `,` does to the current cell once the input is exhausted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EofPolicy {
+    /// Store 0.
+    #[default]
+    Zero,
+    /// Store the maximum cell value (all bits set, i.e. -1).
+    Max,
+    /// Leave the cell unchanged.
+    Unchanged,
+    /// Stop with an error.
+    Error,
+}
+
+impl EofPolicy {
+    /// Returns the value `,` stores for `input`, or `None` to leave the cell
+    /// unchanged. Values are truncated to the cell width when stored.
+    pub(crate) fn resolve(self, input: Option<u8>) -> io::Result<Option<u64>> {
+        match (input, self) {
+            (Some(value), _) => Ok(Some(value as u64)),
+            (None, EofPolicy::Zero) => Ok(Some(0)),
+            (None, EofPolicy::Max) => Ok(Some(u64::MAX)),
+            (None, EofPolicy::Unchanged) => Ok(None),
+            (None, EofPolicy::Error) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past end of input",
+            )),
+        }
+    }
+}
+
+/// Input and output of a running program.
+pub trait Io {
+    /// Reads one byte of input, or `None` at end of input.
+    fn read(&mut self) -> io::Result<Option<u8>>;
+
+    fn write(&mut self, value: u8) -> io::Result<()>;
+
+    /// Writes a run of output bytes, e.g. output computed at compile time.
+    fn write_all(&mut self, values: &[u8]) -> io::Result<()> {
+        values.iter().try_for_each(|&value| self.write(value))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The process's standard input and output.
+pub struct StdIo {
+    stdin: StdinLock<'static>,
+    stdout: StdoutLock<'static>,
+}
+
+impl StdIo {
+    pub fn new() -> Self {
+        StdIo {
+            stdin: io::stdin().lock(),
+            stdout: io::stdout().lock(),
+        }
+    }
+}
+
+impl Default for StdIo {
+    fn default() -> Self {
+        StdIo::new()
+    }
+}
+
+impl Io for StdIo {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        // make prompts visible before blocking on input
+        self.stdout.flush()?;
+        loop {
+            let mut value = 0;
+            if let Err(err) = self.stdin.read_exact(std::slice::from_mut(&mut value)) {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
+                    return Ok(None);
+                }
+                return Err(err);
+            }
+
+            // ignore CR from Window's CRLF
+            if cfg!(target_os = "windows") && value == b'\r' {
+                continue;
+            }
+
+            return Ok(Some(value));
+        }
+    }
+
+    fn write(&mut self, value: u8) -> io::Result<()> {
+        // Writing a non-UTF-8 byte sequence on Windows error out.
+        if cfg!(target_os = "windows") && value >= 128 {
+            return Ok(());
+        }
+        self.stdout.write_all(&[value])
+    }
+
+    fn write_all(&mut self, values: &[u8]) -> io::Result<()> {
+        if cfg!(target_os = "windows") {
+            return values.iter().try_for_each(|&value| self.write(value));
+        }
+        self.stdout.write_all(values)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+/// Adapts any reader and writer pair, e.g. files or sockets.
+pub struct RwIo<R: Read, W: Write> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> RwIo<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        RwIo { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: Read, W: Write> Io for RwIo<R, W> {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        let mut value = 0;
+        match self.reader.read_exact(std::slice::from_mut(&mut value)) {
+            Ok(()) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write(&mut self, value: u8) -> io::Result<()> {
+        self.writer.write_all(&[value])
+    }
+
+    fn write_all(&mut self, values: &[u8]) -> io::Result<()> {
+        self.writer.write_all(values)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads input from a byte slice and collects output in memory.
+pub struct MemoryIo<'a> {
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+impl<'a> MemoryIo<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        MemoryIo {
+            input,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+impl Io for MemoryIo<'_> {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        match self.input.split_first() {
+            Some((&value, rest)) => {
+                self.input = rest;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, value: u8) -> io::Result<()> {
+        self.output.push(value);
+        Ok(())
+    }
+
+    fn write_all(&mut self, values: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(values);
+        Ok(())
+    }
+}
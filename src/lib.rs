@@ -0,0 +1,27 @@
+pub mod analysis;
+pub mod backend;
+pub mod cell;
+pub mod crane_jit;
+pub mod disasm;
+pub mod error;
+pub mod fast_jit;
+mod guard;
+pub mod interpreter;
+pub mod io;
+pub mod limits;
+pub mod options;
+pub mod parser;
+pub mod passes;
+mod runtime;
+mod scan;
+pub mod tape;
+
+pub use backend::{Backend, BackendKind};
+pub use cell::{CellWidth, Overflow};
+pub use error::BfError;
+pub use io::{EofPolicy, Io, MemoryIo, RwIo, StdIo};
+pub use limits::{Limit, Limits};
+pub use options::Options;
+pub use tape::{Tape, TapeMode};
+
+pub const INIT_MEMORY_SIZE: usize = 4096000;
@@ -0,0 +1,181 @@
+//! Resource limits stopping runaway programs.
+
+use crate::cell::CellWidth;
+use crate::error::BfError;
+use crate::io::Io;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Resources a run may use. `None` leaves a resource unlimited.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions executed. The interpreter counts every instruction it
+    /// dispatches; JIT-compiled code counts the top-level instructions on
+    /// entry and every loop iteration as the instructions in its body, so its
+    /// count is approximate.
+    pub steps: Option<u64>,
+    /// Wall-clock time from the start of the run. A program blocked reading
+    /// input is only stopped once the read returns.
+    pub time: Option<Duration>,
+    /// Bytes written. Output up to the limit is still written.
+    pub output: Option<u64>,
+    /// Cells of tape.
+    pub tape: Option<usize>,
+}
+
+impl Limits {
+    /// Bytes of a tape of `cell` cells at the tape limit.
+    pub(crate) fn tape_bytes(&self, cell: CellWidth) -> Option<usize> {
+        self.tape.map(|cells| cells.saturating_mul(cell.bytes()))
+    }
+
+    /// Fails unless a tape of `len` bytes of `cell` cells is within the tape
+    /// limit.
+    pub(crate) fn check_tape(&self, len: usize, cell: CellWidth) -> Result<(), BfError> {
+        match (self.tape, self.tape_bytes(cell)) {
+            (Some(cells), Some(max)) if len > max => {
+                Err(BfError::LimitExceeded(Limit::Tape(cells)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Length in bytes to grow a tape of `cell` cells to: `wanted` within the
+    /// tape limit, but at least `needed`, which must be within it.
+    pub(crate) fn grow_tape(
+        &self,
+        wanted: usize,
+        needed: usize,
+        cell: CellWidth,
+    ) -> Result<usize, BfError> {
+        self.check_tape(needed, cell)?;
+        let max = self.tape_bytes(cell);
+        Ok(max.map_or(wanted, |max| wanted.min(max)).max(needed))
+    }
+
+    /// Whether runs are stopped after a number of steps or some time, which
+    /// generated code has to count and poll for.
+    pub(crate) fn interrupts(&self) -> bool {
+        self.steps.is_some() || self.time.is_some()
+    }
+}
+
+/// The limit a run exceeded, with its value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Limit {
+    Steps(u64),
+    Time(Duration),
+    Output(u64),
+    Tape(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps(steps) => write!(f, "executed more than {} steps", steps),
+            Limit::Time(time) => write!(f, "ran for more than {:?}", time),
+            Limit::Output(bytes) => write!(f, "wrote more than {} bytes", bytes),
+            Limit::Tape(cells) => write!(f, "tape would exceed {} cells", cells),
+        }
+    }
+}
+
+/// What is left of the limits during one run. Dropping it stops the thread
+/// watching the time limit.
+pub(crate) struct Budget {
+    /// Steps left. JIT-compiled code decrements it directly.
+    pub fuel: u64,
+    /// Set once the time limit has passed. JIT-compiled code polls it
+    /// directly.
+    pub cancelled: *const AtomicBool,
+    /// Bytes of output left.
+    output: u64,
+    limits: Limits,
+    flag: Arc<AtomicBool>,
+    watchdog: Option<Watchdog>,
+}
+
+impl Budget {
+    /// Starts a run under `limits`.
+    pub fn start(limits: &Limits) -> Budget {
+        let flag = Arc::new(AtomicBool::new(false));
+        let watchdog = limits.time.map(|time| Watchdog::start(time, flag.clone()));
+        Budget {
+            fuel: limits.steps.unwrap_or(u64::MAX),
+            cancelled: Arc::as_ptr(&flag),
+            output: limits.output.unwrap_or(u64::MAX),
+            limits: *limits,
+            flag,
+            watchdog,
+        }
+    }
+
+    /// Takes one step, failing once the step or time limit is exceeded.
+    #[inline]
+    pub fn step(&mut self) -> Result<(), BfError> {
+        if self.fuel == 0 || self.flag.load(Ordering::Relaxed) {
+            return Err(self.exceeded());
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    /// The error for running out of fuel or being cancelled.
+    pub fn exceeded(&self) -> BfError {
+        let limit = match self.limits.time {
+            Some(time) if self.flag.load(Ordering::Relaxed) => Limit::Time(time),
+            _ => Limit::Steps(self.limits.steps.unwrap_or(u64::MAX)),
+        };
+        BfError::LimitExceeded(limit)
+    }
+
+    /// Writes `bytes` to `io`, or only those within the output limit and
+    /// then fails.
+    pub fn write(&mut self, io: &mut dyn Io, bytes: &[u8]) -> Result<(), BfError> {
+        let allowed = bytes
+            .len()
+            .min(self.output.try_into().unwrap_or(usize::MAX));
+        io.write_all(&bytes[..allowed])?;
+        self.output -= allowed as u64;
+        if allowed < bytes.len() {
+            let limit = self.limits.output.unwrap_or(u64::MAX);
+            return Err(BfError::LimitExceeded(Limit::Output(limit)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Budget {
+    fn drop(&mut self) {
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.stop();
+        }
+    }
+}
+
+/// A thread raising a flag once a time limit has passed.
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Watchdog {
+    fn start(time: Duration, flag: Arc<AtomicBool>) -> Watchdog {
+        let (done, finished) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if finished.recv_timeout(time) == Err(RecvTimeoutError::Timeout) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        });
+        Watchdog { done, thread }
+    }
+
+    fn stop(self) {
+        let _ = self.done.send(());
+        let _ = self.thread.join();
+    }
+}
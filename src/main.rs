@@ -1,15 +1,15 @@
-mod crane_jit;
-mod fast_jit;
-mod interpreter;
-mod parser;
-
-use clap::Parser;
-use interpreter::Interpreter;
+use bfvm::analysis::{self, LoopClass};
+use bfvm::parser::{self, Node};
+use bfvm::passes::{self, PassSet, MAX_OPT_LEVEL};
+use bfvm::{
+    crane_jit, disasm, BackendKind, BfError, CellWidth, EofPolicy, Limits, Options, Overflow,
+    StdIo, TapeMode,
+};
+use clap::{Parser, ValueEnum};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::process::exit;
-
-const INIT_MEMORY_SIZE: usize = 4096000;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -20,12 +20,94 @@ struct Args {
     // Debug mode
     #[arg(short, long)]
     debug: bool,
-    // Fast JIT
-    #[arg(long)]
+    // Execution backend
+    #[arg(short, long, value_enum, default_value_t = BackendKind::Interpreter)]
+    backend: BackendKind,
+    // Same as `--backend fast-jit`, kept for existing invocations
+    #[arg(long, hide = true, conflicts_with_all = ["backend", "crane_jit"])]
     fast_jit: bool,
-    // Crane JIT
-    #[arg(long)]
+    // Same as `--backend crane-jit`, kept for existing invocations
+    #[arg(long, hide = true, conflicts_with = "backend")]
     crane_jit: bool,
+    // What `,` stores once input is exhausted
+    #[arg(long, value_enum, default_value_t = EofPolicy::Zero)]
+    eof: EofPolicy,
+    // Optimization level
+    #[arg(short = 'O', default_value_t = MAX_OPT_LEVEL, value_parser = clap::value_parser!(u8).range(0..=MAX_OPT_LEVEL as i64))]
+    opt_level: u8,
+    // Run a pass regardless of the optimization level
+    #[arg(long, value_name = "PASS")]
+    enable_pass: Vec<String>,
+    // Skip a pass regardless of the optimization level
+    #[arg(long, value_name = "PASS")]
+    disable_pass: Vec<String>,
+    // Print what each optimization pass changed to stderr
+    #[arg(long)]
+    pass_stats: bool,
+    // Print an intermediate representation instead of running the program
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
+    // Check the IR after every optimization pass; always on in debug builds
+    #[arg(long)]
+    verify_ir: bool,
+    // Check tape bounds on every pointer move and cell access in the JIT backends
+    #[arg(long)]
+    checked: bool,
+    // Number of tape cells, which the tape never grows past; sized from the program when omitted
+    #[arg(long, value_name = "CELLS")]
+    tape_size: Option<usize>,
+    // Cell the data pointer starts at
+    #[arg(long, value_name = "CELL", default_value_t = 0)]
+    tape_origin: usize,
+    // What happens at the ends of the tape
+    #[arg(long, value_enum, default_value_t = TapeMode::RightInfinite)]
+    tape: TapeMode,
+    // Bits per cell
+    #[arg(long, value_enum, value_name = "BITS", default_value_t = CellWidth::Bits8)]
+    cell_bits: CellWidth,
+    // What arithmetic does when a cell would go below zero or above its maximum
+    #[arg(long, value_enum, default_value_t = Overflow::Wrap)]
+    overflow: Overflow,
+    // Write cells as UTF-8 encoded code points instead of single bytes
+    #[arg(long)]
+    utf8: bool,
+    // Stop after executing this many instructions
+    #[arg(long, value_name = "STEPS")]
+    max_steps: Option<u64>,
+    // Stop after running for this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    max_time: Option<Duration>,
+    // Stop after writing this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_output: Option<u64>,
+    // Stop when the tape would grow beyond this many cells
+    #[arg(long, value_name = "CELLS")]
+    max_tape_size: Option<usize>,
+}
+
+impl Args {
+    /// Backend selected by `--backend` or one of its aliases.
+    fn backend(&self) -> BackendKind {
+        if self.fast_jit {
+            BackendKind::FastJit
+        } else if self.crane_jit {
+            BackendKind::CraneJit
+        } else {
+            self.backend
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// Optimized nodes
+    Ir,
+    /// Cranelift IR of the crane-jit backend
+    Clif,
+    /// Disassembled machine code of the selected backend
+    Asm,
+    /// Raw machine code of the selected backend
+    Bytes,
 }
 
 fn main() {
@@ -36,82 +118,133 @@ fn main() {
         exit(1)
     });
 
-    if args.fast_jit {
-        let program = match fast_jit::Program::new(&source) {
-            Ok(p) => p,
-            Err(err) => {
-                eprintln!("Compile error: {}", err);
-                exit(1)
+    let passes = pass_set(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+    let options = Options {
+        eof: args.eof,
+        passes,
+        verify_ir: args.verify_ir || cfg!(debug_assertions),
+        checked: args.checked,
+        tape: args.tape,
+        tape_size: args.tape_size,
+        tape_origin: args.tape_origin,
+        cell: args.cell_bits,
+        overflow: args.overflow,
+        utf8: args.utf8,
+        limits: Limits {
+            steps: args.max_steps,
+            time: args.max_time,
+            output: args.max_output,
+            tape: args.max_tape_size,
+        },
+    };
+    let result = parser::parse(&source).and_then(|code| {
+        let (code, stats) = passes::optimize(code, &options)?;
+        if args.pass_stats {
+            for stat in &stats {
+                eprintln!("{}", stat);
             }
-        };
-        program.run().unwrap_or_else(|err| {
-            eprintln!("Runtime error: {}", err);
-            exit(1)
-        });
-    } else if args.crane_jit {
-        let mut program = match crane_jit::Program::new(&source) {
-            Ok(p) => p,
-            Err(err) => {
-                eprintln!("Compile error: {}", err);
-                exit(1)
+            eprintln!("{}", tape_range_stat(&code, args.tape_origin));
+        }
+        for (span, class) in analysis::loops(&code) {
+            if class == LoopClass::Infinite && args.tape != TapeMode::Circular {
+                eprintln!(
+                    "{}:{}: warning: loop never terminates once entered",
+                    args.path, span
+                );
             }
-        };
-        program.run().unwrap_or_else(|err| {
-            eprintln!("Runtime error: {}", err);
-            exit(1)
-        });
-    } else {
-        let mut interpreter = Interpreter::new();
-        interpreter.run(&source).unwrap_or_else(|err| {
-            eprintln!("Runtime error: {}", err);
-        });
-    }
-}
+        }
+        if let Some(kind) = args.emit {
+            return emit(kind, &code, args.backend(), &options);
+        }
+        let mut program = args.backend().compile_ir(&code, &options)?;
+        let mut tape = program.new_tape()?;
+        program.run(&mut tape, &mut StdIo::new())
+    });
 
-fn read_file(path: &str) -> Result<String, String> {
-    let mut buffer = String::new();
-    let mut file = File::open(path).map_err(|e| format!("Could not open file: {:?}", e))?;
-    file.read_to_string(&mut buffer)
-        .map_err(|e| format!("Could not read file: {:?}", e))?;
-    Ok(buffer)
+    if let Err(err) = result {
+        match err.span() {
+            Some(_) => eprintln!("{}:{}", args.path, err),
+            None => eprintln!("{}: {}", args.path, err),
+        }
+        exit(exit_code(&err))
+    }
 }
 
-pub(crate) extern "sysv64" fn write(value: u8) -> *mut std::io::Error {
-    // Writing a non-UTF-8 byte sequence on Windows error out.
-    if cfg!(target_os = "windows") && value >= 128 {
-        return std::ptr::null_mut();
+fn exit_code(err: &BfError) -> i32 {
+    match err {
+        BfError::Syntax { .. } => 2,
+        BfError::Compile(_) => 3,
+        BfError::Io(_) => 4,
+        BfError::TapeOutOfBounds { .. } => 5,
+        BfError::LimitExceeded(_) => 6,
+        BfError::CellOverflow { .. } => 7,
+        BfError::Memory(_) => 8,
     }
+}
 
+/// Writes the representation of `code` selected by `--emit` to stdout.
+fn emit(kind: Emit, code: &[Node], backend: BackendKind, options: &Options) -> Result<(), BfError> {
     let mut stdout = std::io::stdout().lock();
-
-    let result = stdout.write_all(&[value]).and_then(|_| stdout.flush());
-
-    match result {
-        Err(err) => Box::into_raw(Box::new(err)),
-        _ => std::ptr::null_mut(),
+    match kind {
+        Emit::Ir => stdout.write_all(parser::dump(code).as_bytes())?,
+        Emit::Clif => stdout.write_all(crane_jit::Program::clif(code, options)?.as_bytes())?,
+        Emit::Asm | Emit::Bytes => {
+            let program = backend.compile_ir(code, options)?;
+            let bytes = program.code().ok_or_else(|| {
+                let name = backend.to_possible_value().unwrap();
+                BfError::Compile(format!(
+                    "the {} backend does not generate machine code",
+                    name.get_name()
+                ))
+            })?;
+            if kind == Emit::Asm {
+                stdout.write_all(disasm::disassemble(bytes).as_bytes())?;
+            } else {
+                stdout.write_all(bytes)?;
+            }
+        }
     }
+    stdout.flush()?;
+    Ok(())
 }
 
-pub(crate) unsafe extern "sysv64" fn read(buf: *mut u8) -> *mut std::io::Error {
-    let mut stdin = std::io::stdin().lock();
-    loop {
-        let mut value = 0;
-        let err = stdin.read_exact(std::slice::from_mut(&mut value));
-
-        if let Err(err) = err {
-            if err.kind() != std::io::ErrorKind::UnexpectedEof {
-                return Box::into_raw(Box::new(err));
-            }
-            value = 0;
+/// The tape range of `code` starting at `origin`, reported by `--pass-stats`
+/// after the statistics of the passes.
+fn tape_range_stat(code: &[Node], origin: usize) -> String {
+    let name = "tape-range";
+    match analysis::tape_range(code) {
+        Some(range) if origin.checked_add_signed(*range.start()).is_some() => {
+            format!("{:<12} cells {:?}", name, range)
         }
+        Some(range) => format!("{:<12} cells {:?} reach below the tape", name, range),
+        None => format!("{:<12} unbounded, accesses are checked or guarded", name),
+    }
+}
 
-        // ignore CR from Window's CRLF
-        if cfg!(target_os = "windows") && value == b'\r' {
-            continue;
-        }
+/// Passes selected by `-O`, adjusted by `--enable-pass` and `--disable-pass`.
+fn pass_set(args: &Args) -> Result<PassSet, String> {
+    let mut passes = PassSet::for_level(args.opt_level);
+    for name in &args.enable_pass {
+        passes.enable(name)?;
+    }
+    for name in &args.disable_pass {
+        passes.disable(name)?;
+    }
+    Ok(passes)
+}
 
-        *buf = value;
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{}", e))
+}
 
-        return std::ptr::null_mut();
-    }
+fn read_file(path: &str) -> Result<String, String> {
+    let mut buffer = String::new();
+    let mut file = File::open(path).map_err(|e| format!("Could not open file: {:?}", e))?;
+    file.read_to_string(&mut buffer)
+        .map_err(|e| format!("Could not read file: {:?}", e))?;
+    Ok(buffer)
 }
@@ -0,0 +1,53 @@
+use crate::cell::{CellWidth, Overflow};
+use crate::io::EofPolicy;
+use crate::limits::Limits;
+use crate::passes::PassSet;
+use crate::tape::TapeMode;
+
+/// Settings shared by every backend.
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+    pub eof: EofPolicy,
+    /// Optimization passes run by `Backend::compile`.
+    pub passes: PassSet,
+    /// Check the IR after every pass. On by default in debug builds.
+    pub verify_ir: bool,
+    /// Check every pointer move and cell access in the JIT backends against the
+    /// tape bounds.
+    pub checked: bool,
+    /// What happens at the ends of the tape. The JIT backends do not support
+    /// circular tapes.
+    pub tape: TapeMode,
+    /// Cells of the tape. A given size is fixed on every backend: the tape
+    /// never grows, and moving past its ends is out of bounds unless the tape
+    /// is circular. Sized from the program and the tape mode when `None`.
+    pub tape_size: Option<usize>,
+    /// Cell the data pointer starts at. Ignored by bidirectional tapes of
+    /// unknown size, which start in their middle.
+    pub tape_origin: usize,
+    pub cell: CellWidth,
+    /// What arithmetic does at the ends of the cell range.
+    pub overflow: Overflow,
+    /// Write cells as UTF-8 encoded code points rather than their low byte.
+    pub utf8: bool,
+    /// Resources a run may use.
+    pub limits: Limits,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            eof: EofPolicy::default(),
+            passes: PassSet::default(),
+            verify_ir: cfg!(debug_assertions),
+            checked: false,
+            tape: TapeMode::default(),
+            tape_size: None,
+            tape_origin: 0,
+            cell: CellWidth::default(),
+            overflow: Overflow::default(),
+            utf8: false,
+            limits: Limits::default(),
+        }
+    }
+}
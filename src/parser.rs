@@ -1,60 +1,213 @@
-#[derive(Debug, Copy, Clone)]
-pub enum Node {
-    Increment(u8),
-    Decrement(u8),
-    Next(usize),
-    Prev(usize),
-    Write,
-    Read,
-    LoopBegin,
-    LoopEnd,
-}
-pub(crate) fn parse(source: &str) -> Result<Vec<Node>, String> {
+use crate::error::BfError;
+use std::fmt;
+
+/// Location of an instruction in the source text.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset from the start of the source.
+    pub offset: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in characters.
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Adds a wrapping amount to the cell at `offset` from the data pointer.
+    /// Constants are kept modulo 2^64 and truncated to the cell width by the
+    /// backends.
+    Add { offset: isize, value: i64 },
+    /// Moves the data pointer by a signed distance.
+    Move(isize),
+    /// Stores a constant in the cell at `offset`, e.g. `[-]` or `[-]+++`.
+    Set { offset: isize, value: i64 },
+    /// Adds the current cell times `factor` to the cell at `offset`. A run of
+    /// them replaces a loop, so backends skip the whole run when the current
+    /// cell is zero rather than touch cells the loop would not.
+    MulAdd { offset: isize, factor: i64 },
+    /// Moves the data pointer by `stride` until it reaches a zero cell,
+    /// e.g. `[>]` or `[<<<<]`.
+    Scan(isize),
+    /// Outputs the cell at `offset`.
+    Write { offset: isize },
+    /// Reads one byte of input into the cell at `offset`.
+    Read { offset: isize },
+    /// Outputs constant bytes computed at compile time.
+    Output(Vec<u8>),
+    /// Runs the body while the current cell is non-zero.
+    Loop(Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub kind: NodeKind,
+    /// Location of the instruction, or of the `[` for loops.
+    pub span: Span,
+}
+
+/// Parses `source` into a tree of nodes with matched brackets.
+pub fn parse(source: &str) -> Result<Vec<Node>, BfError> {
+    // bodies of the loops being parsed, with the spans of their `[`
+    let mut open_loops: Vec<(Vec<Node>, Span)> = Vec::new();
     let mut code = Vec::new();
-    for c in source.chars() {
-        match c {
-            '+' => code.push(Node::Increment(1)),
-            '-' => code.push(Node::Decrement(1)),
-            '>' => code.push(Node::Next(1)),
-            '<' => code.push(Node::Prev(1)),
-            '.' => code.push(Node::Write),
-            ',' => code.push(Node::Read),
+    let mut line = 1;
+    let mut column = 0;
+    for (offset, c) in source.char_indices() {
+        if c == '\n' {
+            line += 1;
+            column = 0;
+            continue;
+        }
+        column += 1;
+
+        let span = Span {
+            offset,
+            line,
+            column,
+        };
+        let kind = match c {
+            '+' => NodeKind::Add {
+                offset: 0,
+                value: 1,
+            },
+            '-' => NodeKind::Add {
+                offset: 0,
+                value: -1,
+            },
+            '>' => NodeKind::Move(1),
+            '<' => NodeKind::Move(-1),
+            '.' => NodeKind::Write { offset: 0 },
+            ',' => NodeKind::Read { offset: 0 },
             '[' => {
-                code.push(Node::LoopBegin);
+                open_loops.push((std::mem::take(&mut code), span));
+                continue;
             }
             ']' => {
-                code.push(Node::LoopEnd);
+                let (outer, begin_span) =
+                    open_loops.pop().ok_or_else(|| unmatched_loop_end(span))?;
+                let body = std::mem::replace(&mut code, outer);
+                code.push(Node {
+                    kind: NodeKind::Loop(body),
+                    span: begin_span,
+                });
+                continue;
             }
             _ => continue,
-        }
+        };
+        code.push(Node { kind, span });
+    }
+    if let Some(&(_, span)) = open_loops.last() {
+        return Err(unmatched_loop_begin(span));
     }
-    code = pass_simplify(&code);
     Ok(code)
 }
 
-fn pass_simplify(code: &Vec<Node>) -> Vec<Node> {
-    let mut result = Vec::new();
-    for next_op in code {
-        let prev_op = result.last();
+fn unmatched_loop_begin(span: Span) -> BfError {
+    BfError::syntax(span, "unmatched '['")
+}
+
+fn unmatched_loop_end(span: Span) -> BfError {
+    BfError::syntax(span, "unmatched ']'")
+}
+
+/// Renders `code` one node per line, prefixed by its span, with loop bodies
+/// indented under their `loop` line.
+pub fn dump(code: &[Node]) -> String {
+    let mut out = String::new();
+    dump_level(&mut out, code, 0);
+    out
+}
+
+fn dump_level(out: &mut String, code: &[Node], depth: usize) {
+    use std::fmt::Write;
 
-        let combined = match (prev_op, next_op) {
-            (Some(Node::Increment(x)), Node::Increment(y)) => {
-                Some(Node::Increment(x.wrapping_add(*y)))
+    for node in code {
+        let indent = "  ".repeat(depth);
+        let span = node.span.to_string();
+        let _ = match node.kind {
+            NodeKind::Add { offset, value } => {
+                writeln!(out, "{:>8}  {}add [{}] {}", span, indent, offset, value)
+            }
+            NodeKind::Move(n) => writeln!(out, "{:>8}  {}move {}", span, indent, n),
+            NodeKind::Set { offset, value } => {
+                writeln!(out, "{:>8}  {}set [{}] {}", span, indent, offset, value)
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                writeln!(out, "{:>8}  {}muladd [{}] {}", span, indent, offset, factor)
             }
-            (Some(Node::Decrement(x)), Node::Decrement(y)) => {
-                Some(Node::Decrement(x.wrapping_add(*y)))
+            NodeKind::Scan(stride) => writeln!(out, "{:>8}  {}scan {}", span, indent, stride),
+            NodeKind::Write { offset } => {
+                writeln!(out, "{:>8}  {}write [{}]", span, indent, offset)
+            }
+            NodeKind::Read { offset } => writeln!(out, "{:>8}  {}read [{}]", span, indent, offset),
+            NodeKind::Output(ref bytes) => writeln!(
+                out,
+                "{:>8}  {}output \"{}\"",
+                span,
+                indent,
+                bytes.escape_ascii()
+            ),
+            NodeKind::Loop(ref body) => {
+                let _ = writeln!(out, "{:>8}  {}loop", span, indent);
+                dump_level(out, body, depth + 1);
+                Ok(())
             }
-            (Some(Node::Prev(x)), Node::Prev(y)) => Some(Node::Prev(x.wrapping_add(*y))),
-            (Some(Node::Next(x)), Node::Next(y)) => Some(Node::Next(x.wrapping_add(*y))),
-            _ => None,
         };
+    }
+}
 
-        if let Some(new_op) = combined {
-            result.pop();
-            result.push(new_op);
-        } else {
-            result.push(*next_op);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(code: &[Node]) -> Vec<(usize, usize, usize)> {
+        code.iter()
+            .map(|node| (node.span.offset, node.span.line, node.span.column))
+            .collect()
+    }
+
+    #[test]
+    fn spans_count_lines_and_characters() {
+        // columns count characters, comments included, and restart after
+        // every newline
+        let code = parse("+\n é>\n\n  .").unwrap();
+        assert_eq!(spans(&code), [(0, 1, 1), (5, 2, 3), (10, 4, 3)]);
+    }
+
+    #[test]
+    fn loops_take_the_span_of_their_opening_bracket() {
+        let code = parse("+\n[-\n]").unwrap();
+        assert_eq!(spans(&code), [(0, 1, 1), (2, 2, 1)]);
+        let NodeKind::Loop(ref body) = code[1].kind else {
+            panic!("expected a loop, got {:?}", code[1]);
+        };
+        assert_eq!(spans(body), [(3, 2, 2)]);
+    }
+
+    #[test]
+    fn unclosed_loops_report_the_outermost_bracket() {
+        let err = parse("+\n[[]").unwrap_err();
+        assert_eq!(
+            err.span(),
+            Some(Span {
+                offset: 2,
+                line: 2,
+                column: 1
+            })
+        );
+        assert_eq!(err.to_string(), "2:1: unmatched '['");
+    }
+
+    #[test]
+    fn stray_closing_brackets_are_reported() {
+        let err = parse("[]\n+]").unwrap_err();
+        assert_eq!(err.to_string(), "2:2: unmatched ']'");
     }
-    result
 }
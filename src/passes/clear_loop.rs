@@ -0,0 +1,63 @@
+use super::{add_known, map_levels};
+use crate::cell::Overflow;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind};
+
+/// Replaces clear loops such as `[-]` and `[+]` with `Set(0)` and folds any
+/// `+`/`-` that follows into the stored value. A loop adding an odd amount
+/// always reaches zero because the amount is invertible modulo any power of
+/// two, whatever the cell width. Cells that do not wrap are only cleared by
+/// subtracting: by one in error mode, where other amounts may overflow
+/// instead, and by any amount when they saturate at zero.
+pub(crate) fn run(code: Vec<Node>, options: &Options) -> Vec<Node> {
+    map_levels(code, &|code| lower_clear_loops(code, options))
+}
+
+fn lower_clear_loops(code: Vec<Node>, options: &Options) -> Vec<Node> {
+    let mut result: Vec<Node> = Vec::new();
+    for node in code {
+        let kind = match &node.kind {
+            NodeKind::Loop(body) if is_clear_loop(body, options.overflow) => NodeKind::Set {
+                offset: 0,
+                value: 0,
+            },
+            _ => node.kind,
+        };
+
+        let folded = match (result.last().map(|prev| &prev.kind), &kind) {
+            (
+                Some(&NodeKind::Set { offset, value }),
+                &NodeKind::Add {
+                    offset: add_offset,
+                    value: n,
+                },
+            ) if offset == add_offset => {
+                add_known(value, 1, n, options).map(|value| NodeKind::Set { offset, value })
+            }
+            _ => None,
+        };
+
+        match folded {
+            Some(kind) => result.last_mut().unwrap().kind = kind,
+            None => result.push(Node {
+                kind,
+                span: node.span,
+            }),
+        }
+    }
+    result
+}
+
+fn is_clear_loop(body: &[Node], overflow: Overflow) -> bool {
+    match body {
+        [Node {
+            kind: NodeKind::Add { offset: 0, value },
+            ..
+        }] => match overflow {
+            Overflow::Wrap => value % 2 != 0,
+            Overflow::Error => *value == -1,
+            Overflow::Saturate => *value < 0,
+        },
+        _ => false,
+    }
+}
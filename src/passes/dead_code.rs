@@ -0,0 +1,292 @@
+use super::{add_known, count};
+use crate::cell::Overflow;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind};
+use std::collections::BTreeMap;
+
+/// Removes code that cannot have an effect, using what is known about cell
+/// values: every cell is zero at program start and the current cell is zero
+/// after a loop. Loops and scans over a zero cell never run, so comment loops
+/// at the start of a program and loops right after another loop's `]` are
+/// deleted, as are stores that equal the known value. Adds and stores
+/// overwritten by a later store before anything reads the cell are dead too,
+/// e.g. `+++[-]` becomes `[-]`. Removing a dead add can make the store after
+/// it redundant, so the pass repeats until nothing changes. Overwritten adds
+/// are kept with `--overflow=error`, as they may still overflow.
+pub(crate) fn run(mut code: Vec<Node>, options: &Options) -> Vec<Node> {
+    loop {
+        let before = count(&code);
+        code = eliminate(
+            code,
+            Cells {
+                known: BTreeMap::new(),
+                rest: Some(0),
+            },
+            options,
+        );
+        if count(&code) == before {
+            return code;
+        }
+    }
+}
+
+/// Cell values known at a point of the program, relative to the data pointer.
+struct Cells {
+    /// Cells whose value is known (`Some`) or known to be unknown (`None`).
+    known: BTreeMap<isize, Option<i64>>,
+    /// Value of every cell missing from `known`, if it is known.
+    rest: Option<i64>,
+}
+
+impl Cells {
+    fn get(&self, offset: isize) -> Option<i64> {
+        self.known.get(&offset).copied().unwrap_or(self.rest)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<i64>) {
+        self.known.insert(offset, value);
+    }
+
+    fn shift(&mut self, n: isize) {
+        self.known = self
+            .known
+            .iter()
+            .map(|(&offset, &value)| (offset - n, value))
+            .collect();
+    }
+
+    /// State after a loop or scan, which leave only the current cell known.
+    fn after_loop() -> Cells {
+        Cells {
+            known: BTreeMap::from([(0, Some(0))]),
+            rest: None,
+        }
+    }
+}
+
+fn eliminate(code: Vec<Node>, mut cells: Cells, options: &Options) -> Vec<Node> {
+    let mut result: Vec<Node> = Vec::new();
+    for node in code {
+        let kind = match node.kind {
+            NodeKind::Add { offset, value } => {
+                if let Some(old) = cells.get(offset) {
+                    cells.set(offset, add_known(old, 1, value, options));
+                }
+                node.kind
+            }
+            NodeKind::Set { offset, value } => {
+                if cells.get(offset) == Some(value) {
+                    continue;
+                }
+                remove_dead_stores(&mut result, offset, options.overflow);
+                cells.set(offset, Some(value));
+                node.kind
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                match cells.get(0) {
+                    Some(0) => continue,
+                    Some(counter) => {
+                        let target = cells
+                            .get(offset)
+                            .and_then(|target| add_known(target, counter, factor, options));
+                        cells.set(offset, target);
+                    }
+                    None => cells.set(offset, None),
+                }
+                node.kind
+            }
+            NodeKind::Move(n) => {
+                cells.shift(n);
+                node.kind
+            }
+            NodeKind::Scan(_) => {
+                if cells.get(0) == Some(0) {
+                    continue;
+                }
+                cells = Cells::after_loop();
+                node.kind
+            }
+            NodeKind::Write { .. } | NodeKind::Output(_) => node.kind,
+            NodeKind::Read { offset } => {
+                cells.set(offset, None);
+                node.kind
+            }
+            NodeKind::Loop(body) => {
+                if cells.get(0) == Some(0) {
+                    continue;
+                }
+                cells = Cells::after_loop();
+                let body = eliminate(
+                    body,
+                    Cells {
+                        known: BTreeMap::new(),
+                        rest: None,
+                    },
+                    options,
+                );
+                NodeKind::Loop(body)
+            }
+        };
+        result.push(Node {
+            kind,
+            span: node.span,
+        });
+    }
+    result
+}
+
+/// Removes the adds and stores to the cell at `offset` that a store about to
+/// be appended to `code` overwrites before anything reads it.
+fn remove_dead_stores(code: &mut Vec<Node>, mut offset: isize, overflow: Overflow) {
+    let mut i = code.len();
+    while i > 0 {
+        i -= 1;
+        match code[i].kind {
+            NodeKind::Move(n) => offset += n,
+            NodeKind::Add { offset: o, .. } if overflow == Overflow::Error => {
+                if o == offset {
+                    return;
+                }
+            }
+            NodeKind::Add { offset: o, .. } | NodeKind::Set { offset: o, .. } => {
+                if o == offset {
+                    code.remove(i);
+                }
+            }
+            NodeKind::Write { offset: o } | NodeKind::Read { offset: o } => {
+                if o == offset {
+                    return;
+                }
+            }
+            NodeKind::MulAdd { offset: o, .. } => {
+                if o == offset || offset == 0 {
+                    return;
+                }
+            }
+            NodeKind::Output(_) => {}
+            NodeKind::Scan(_) | NodeKind::Loop(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Span;
+
+    fn nodes(kinds: Vec<NodeKind>) -> Vec<Node> {
+        kinds
+            .into_iter()
+            .map(|kind| Node {
+                kind,
+                span: Span::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dead_stores_are_found_across_moves() {
+        // +>+< then a store to the first cell, seen from the second one
+        let mut code = nodes(vec![
+            NodeKind::Add {
+                offset: 0,
+                value: 1,
+            },
+            NodeKind::Move(1),
+            NodeKind::Add {
+                offset: 0,
+                value: 1,
+            },
+        ]);
+        remove_dead_stores(&mut code, -1, Overflow::Wrap);
+        let expected = nodes(vec![
+            NodeKind::Move(1),
+            NodeKind::Add {
+                offset: 0,
+                value: 1,
+            },
+        ]);
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn dead_stores_stop_at_reads_of_the_cell() {
+        let code = nodes(vec![
+            NodeKind::Set {
+                offset: 2,
+                value: 7,
+            },
+            NodeKind::Move(-1),
+            NodeKind::Write { offset: 3 },
+            NodeKind::Move(-1),
+            NodeKind::Add {
+                offset: 4,
+                value: 1,
+            },
+        ]);
+        let mut removed = code.clone();
+        remove_dead_stores(&mut removed, 4, Overflow::Wrap);
+        assert_eq!(removed, code[..4]);
+
+        // the write reads the cell the earlier set stores to
+        let mut kept = code[..4].to_vec();
+        remove_dead_stores(&mut kept, 4, Overflow::Wrap);
+        assert_eq!(kept, code[..4]);
+    }
+
+    #[test]
+    fn dead_stores_stop_at_mul_adds_reading_the_cell() {
+        let code = nodes(vec![
+            NodeKind::Add {
+                offset: 1,
+                value: 3,
+            },
+            NodeKind::Add {
+                offset: 2,
+                value: 3,
+            },
+            NodeKind::Add {
+                offset: 0,
+                value: 3,
+            },
+            NodeKind::Move(1),
+            NodeKind::MulAdd {
+                offset: 1,
+                factor: 2,
+            },
+        ]);
+        // the counter and the target are read, the cell before them is not
+        for offset in [0, 1] {
+            let mut kept = code.clone();
+            remove_dead_stores(&mut kept, offset, Overflow::Wrap);
+            assert_eq!(kept, code);
+        }
+        let mut removed = code.clone();
+        remove_dead_stores(&mut removed, -1, Overflow::Wrap);
+        assert_eq!(
+            removed,
+            code[..2]
+                .iter()
+                .chain(&code[3..])
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn overwritten_adds_are_kept_when_they_may_overflow() {
+        let code = nodes(vec![
+            NodeKind::Add {
+                offset: 1,
+                value: 5,
+            },
+            NodeKind::Move(1),
+        ]);
+        let mut kept = code.clone();
+        remove_dead_stores(&mut kept, 0, Overflow::Error);
+        assert_eq!(kept, code);
+        let mut removed = code.clone();
+        remove_dead_stores(&mut removed, 0, Overflow::Saturate);
+        assert_eq!(removed, code[1..]);
+    }
+}
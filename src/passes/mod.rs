@@ -0,0 +1,328 @@
+//! Optimization passes over the parsed IR and the pass manager running them.
+
+mod clear_loop;
+mod dead_code;
+mod mul_loop;
+mod offsets;
+mod pre_eval;
+mod scan_loop;
+mod simplify;
+mod verify;
+
+use crate::cell::Overflow;
+use crate::error::BfError;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind};
+use crate::tape::TapeMode;
+use std::fmt;
+
+/// The highest `-O` level; it enables every pass.
+pub const MAX_OPT_LEVEL: u8 = 3;
+
+/// A named transformation of a whole program.
+pub struct Pass {
+    pub name: &'static str,
+    /// Lowest `-O` level that enables the pass.
+    pub level: u8,
+    /// Whether the pass reasons about what cells hold at fixed offsets from
+    /// the start, which alias on a circular tape. Such passes are skipped for
+    /// circular tapes.
+    pub linear: bool,
+    run: fn(Vec<Node>, &Options) -> Vec<Node>,
+}
+
+/// Every pass, in the order they run.
+pub static PASSES: &[Pass] = &[
+    Pass {
+        name: "simplify",
+        level: 1,
+        linear: false,
+        run: simplify::run,
+    },
+    Pass {
+        name: "mul-loop",
+        level: 2,
+        linear: true,
+        run: mul_loop::run,
+    },
+    Pass {
+        name: "clear-loop",
+        level: 1,
+        linear: false,
+        run: clear_loop::run,
+    },
+    Pass {
+        name: "scan-loop",
+        level: 2,
+        linear: false,
+        run: |code, _| scan_loop::run(code),
+    },
+    Pass {
+        name: "dead-code",
+        level: 1,
+        linear: true,
+        run: dead_code::run,
+    },
+    Pass {
+        name: "offsets",
+        level: 3,
+        linear: false,
+        run: |code, _| offsets::run(code),
+    },
+    Pass {
+        name: "pre-eval",
+        level: 3,
+        linear: true,
+        run: pre_eval::run,
+    },
+];
+
+/// The set of passes to run, one bit per entry of [`PASSES`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PassSet(u32);
+
+impl PassSet {
+    /// Passes enabled by `-O<level>`.
+    pub fn for_level(level: u8) -> PassSet {
+        let bits = PASSES
+            .iter()
+            .enumerate()
+            .filter(|(_, pass)| pass.level <= level)
+            .fold(0, |bits, (i, _)| bits | 1 << i);
+        PassSet(bits)
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<(), String> {
+        self.0 |= 1 << index_of(name)?;
+        Ok(())
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        self.0 &= !(1 << index_of(name)?);
+        Ok(())
+    }
+
+    pub fn contains(self, name: &str) -> bool {
+        index_of(name).is_ok_and(|i| self.0 & 1 << i != 0)
+    }
+}
+
+impl Default for PassSet {
+    fn default() -> Self {
+        PassSet::for_level(MAX_OPT_LEVEL)
+    }
+}
+
+fn index_of(name: &str) -> Result<usize, String> {
+    PASSES
+        .iter()
+        .position(|pass| pass.name == name)
+        .ok_or_else(|| format!("unknown pass '{}'", name))
+}
+
+/// What one pass changed.
+#[derive(Debug, Copy, Clone)]
+pub struct PassStats {
+    pub name: &'static str,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    /// Loops replaced by straight-line nodes.
+    pub loops_rewritten: usize,
+}
+
+impl PassStats {
+    /// Net number of nodes removed; negative if the pass added nodes.
+    pub fn nodes_removed(&self) -> isize {
+        self.nodes_before as isize - self.nodes_after as isize
+    }
+}
+
+impl fmt::Display for PassStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<12} nodes {} -> {} ({} removed), {} loops rewritten",
+            self.name,
+            self.nodes_before,
+            self.nodes_after,
+            self.nodes_removed(),
+            self.loops_rewritten
+        )
+    }
+}
+
+/// Runs the passes selected by `options` and suited to its tape over `code`
+/// in order, recording what each one changed. With `options.verify_ir` the
+/// IR is checked after parsing and after every pass.
+pub fn optimize(
+    mut code: Vec<Node>,
+    options: &Options,
+) -> Result<(Vec<Node>, Vec<PassStats>), BfError> {
+    if options.verify_ir {
+        verify::run(&code, "parse")?;
+    }
+    let mut stats = Vec::new();
+    for (i, pass) in PASSES.iter().enumerate() {
+        if options.passes.0 & 1 << i == 0 || pass.linear && options.tape == TapeMode::Circular {
+            continue;
+        }
+        let (nodes_before, loops_before) = count(&code);
+        code = (pass.run)(code, options);
+        if options.verify_ir {
+            verify::run(&code, pass.name)?;
+        }
+        let (nodes_after, loops_after) = count(&code);
+        stats.push(PassStats {
+            name: pass.name,
+            nodes_before,
+            nodes_after,
+            loops_rewritten: loops_before.saturating_sub(loops_after),
+        });
+    }
+    Ok((code, stats))
+}
+
+/// Value of a cell known to hold `value` after adding `counter * factor` to
+/// it, or `None` if that overflows with `--overflow=error`. Known values wrap
+/// modulo 2^64 like the constants in the IR unless cells do not wrap.
+fn add_known(value: i64, counter: i64, factor: i64, options: &Options) -> Option<i64> {
+    match options.overflow {
+        Overflow::Wrap => Some(value.wrapping_add(counter.wrapping_mul(factor))),
+        overflow => {
+            let max = options.cell.max();
+            let n = (counter as u64 & max) as i128 * factor as i128;
+            overflow
+                .add(value as u64 & max, n, max)
+                .map(|value| value as i64)
+        }
+    }
+}
+
+/// Counts all nodes and the loops among them, including nested ones.
+fn count(code: &[Node]) -> (usize, usize) {
+    code.iter()
+        .fold((0, 0), |(nodes, loops), node| match &node.kind {
+            NodeKind::Loop(body) => {
+                let (body_nodes, body_loops) = count(body);
+                (nodes + 1 + body_nodes, loops + 1 + body_loops)
+            }
+            _ => (nodes + 1, loops),
+        })
+}
+
+/// Applies `rewrite` to every loop body, innermost first, and then to `code`.
+fn map_levels(code: Vec<Node>, rewrite: &dyn Fn(Vec<Node>) -> Vec<Node>) -> Vec<Node> {
+    let code = code
+        .into_iter()
+        .map(|node| match node.kind {
+            NodeKind::Loop(body) => Node {
+                kind: NodeKind::Loop(map_levels(body, rewrite)),
+                span: node.span,
+            },
+            _ => node,
+        })
+        .collect();
+    rewrite(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn names(passes: PassSet) -> Vec<&'static str> {
+        PASSES
+            .iter()
+            .map(|pass| pass.name)
+            .filter(|&name| passes.contains(name))
+            .collect()
+    }
+
+    #[test]
+    fn levels_enable_the_passes_up_to_them() {
+        assert!(names(PassSet::for_level(0)).is_empty());
+        assert_eq!(
+            names(PassSet::for_level(1)),
+            ["simplify", "clear-loop", "dead-code"]
+        );
+        assert_eq!(
+            names(PassSet::for_level(2)),
+            [
+                "simplify",
+                "mul-loop",
+                "clear-loop",
+                "scan-loop",
+                "dead-code"
+            ]
+        );
+        assert_eq!(names(PassSet::for_level(MAX_OPT_LEVEL)).len(), PASSES.len());
+        assert_eq!(PassSet::default(), PassSet::for_level(MAX_OPT_LEVEL));
+    }
+
+    #[test]
+    fn passes_are_toggled_by_name() {
+        let mut passes = PassSet::for_level(1);
+        passes.enable("offsets").unwrap();
+        passes.disable("simplify").unwrap();
+        assert_eq!(names(passes), ["clear-loop", "dead-code", "offsets"]);
+        // toggling twice changes nothing
+        passes.enable("offsets").unwrap();
+        passes.disable("simplify").unwrap();
+        assert_eq!(names(passes), ["clear-loop", "dead-code", "offsets"]);
+    }
+
+    #[test]
+    fn unknown_pass_names_are_rejected() {
+        let mut passes = PassSet::for_level(1);
+        assert_eq!(
+            passes.enable("loop-unroll"),
+            Err("unknown pass 'loop-unroll'".into())
+        );
+        assert_eq!(passes.disable(""), Err("unknown pass ''".into()));
+        assert_eq!(passes, PassSet::for_level(1));
+        assert!(!passes.contains("loop-unroll"));
+    }
+
+    #[test]
+    fn stats_record_each_pass_that_ran() {
+        let options = Options {
+            passes: PassSet::for_level(1),
+            ..Options::default()
+        };
+        let (code, stats) = optimize(parse("+-[-]>.").unwrap(), &options).unwrap();
+        let stats: Vec<_> = stats
+            .iter()
+            .map(|stat| {
+                (
+                    stat.name,
+                    stat.nodes_before,
+                    stat.nodes_after,
+                    stat.loops_rewritten,
+                )
+            })
+            .collect();
+        assert_eq!(
+            stats,
+            [
+                ("simplify", 6, 4, 0),
+                ("clear-loop", 4, 3, 1),
+                ("dead-code", 3, code.len(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_print_one_line_per_pass() {
+        let stats = PassStats {
+            name: "simplify",
+            nodes_before: 6,
+            nodes_after: 8,
+            loops_rewritten: 1,
+        };
+        assert_eq!(stats.nodes_removed(), -2);
+        assert_eq!(
+            stats.to_string(),
+            "simplify     nodes 6 -> 8 (-2 removed), 1 loops rewritten"
+        );
+    }
+}
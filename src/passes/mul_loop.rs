@@ -0,0 +1,84 @@
+use super::map_levels;
+use crate::cell::Overflow;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind};
+use std::collections::BTreeMap;
+
+/// Replaces balanced loops that only add and move, and change the loop
+/// counter by exactly ±1, e.g. `[->+>+++<<]`, with one `MulAdd` per target
+/// cell followed by `Set(0)` for the counter. Unless cells wrap, the counter
+/// must count down, and every cell must only be added to or subtracted from,
+/// so that it overflows during the loop exactly when its final value would.
+pub(crate) fn run(code: Vec<Node>, options: &Options) -> Vec<Node> {
+    map_levels(code, &|code| lower_mul_loops(code, options.overflow))
+}
+
+fn lower_mul_loops(code: Vec<Node>, overflow: Overflow) -> Vec<Node> {
+    let mut result = Vec::new();
+    for node in code {
+        let targets = match &node.kind {
+            NodeKind::Loop(body) => mul_loop_targets(body, overflow),
+            _ => None,
+        };
+        let Some(targets) = targets else {
+            result.push(node);
+            continue;
+        };
+
+        for (offset, factor) in targets {
+            result.push(Node {
+                kind: NodeKind::MulAdd { offset, factor },
+                span: node.span,
+            });
+        }
+        result.push(Node {
+            kind: NodeKind::Set {
+                offset: 0,
+                value: 0,
+            },
+            span: node.span,
+        });
+    }
+    result
+}
+
+/// Returns the factor applied to each target offset if `body` is the body of
+/// a multiply loop.
+fn mul_loop_targets(body: &[Node], overflow: Overflow) -> Option<BTreeMap<isize, i64>> {
+    let mut offset = 0isize;
+    let mut deltas = BTreeMap::new();
+    for node in body {
+        match node.kind {
+            NodeKind::Add {
+                offset: add_offset,
+                value,
+            } => {
+                let delta = deltas.entry(offset + add_offset).or_insert(0i64);
+                if overflow == Overflow::Wrap {
+                    *delta = delta.wrapping_add(value);
+                } else if *delta == 0 || delta.signum() == value.signum() {
+                    *delta = delta.checked_add(value)?;
+                } else {
+                    return None;
+                }
+            }
+            NodeKind::Move(n) => offset += n,
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    // each iteration adds `step` to the counter, so the loop runs
+    // `-counter * step` times (`step` is its own inverse)
+    let step = deltas.remove(&0)?;
+    if step != -1 && (step != 1 || overflow != Overflow::Wrap) {
+        return None;
+    }
+    deltas.retain(|_, delta| *delta != 0);
+    for delta in deltas.values_mut() {
+        *delta = delta.wrapping_mul(step.wrapping_neg());
+    }
+    Some(deltas)
+}
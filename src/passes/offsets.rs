@@ -0,0 +1,63 @@
+use super::map_levels;
+use crate::parser::{Node, NodeKind, Span};
+
+/// Rewrites straight-line runs of moves and cell operations to address cells
+/// relative to the pointer at the start of the run, e.g. `>+>++<<-` becomes
+/// `Add { offset: 1, .. }`, `Add { offset: 2, .. }`, `Add { offset: 0, .. }`.
+/// The net movement is applied by a single `Move` before the next loop,
+/// `MulAdd` or `Scan`, or at the end of the block. Offsets wrap around a
+/// circular tape just like moves, so the rewrite holds there too.
+pub(crate) fn run(code: Vec<Node>) -> Vec<Node> {
+    map_levels(code, &defer_moves)
+}
+
+fn defer_moves(code: Vec<Node>) -> Vec<Node> {
+    let mut result = Vec::new();
+    // net movement not applied yet, with the span of the first deferred move
+    let mut pending: Option<(isize, Span)> = None;
+    for node in code {
+        let shift = pending.map_or(0, |(n, _)| n);
+        let kind = match node.kind {
+            NodeKind::Move(n) => {
+                let span = pending.map_or(node.span, |(_, span)| span);
+                pending = Some((shift + n, span));
+                continue;
+            }
+            NodeKind::Add { offset, value } => NodeKind::Add {
+                offset: offset + shift,
+                value,
+            },
+            NodeKind::Set { offset, value } => NodeKind::Set {
+                offset: offset + shift,
+                value,
+            },
+            NodeKind::Write { offset } => NodeKind::Write {
+                offset: offset + shift,
+            },
+            NodeKind::Read { offset } => NodeKind::Read {
+                offset: offset + shift,
+            },
+            kind => {
+                flush_move(&mut result, pending.take());
+                kind
+            }
+        };
+        result.push(Node {
+            kind,
+            span: node.span,
+        });
+    }
+    flush_move(&mut result, pending);
+    result
+}
+
+fn flush_move(code: &mut Vec<Node>, pending: Option<(isize, Span)>) {
+    if let Some((n, span)) = pending {
+        if n != 0 {
+            code.push(Node {
+                kind: NodeKind::Move(n),
+                span,
+            });
+        }
+    }
+}
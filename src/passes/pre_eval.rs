@@ -0,0 +1,276 @@
+use crate::cell::{self, Overflow};
+use crate::options::Options;
+use crate::parser::{Node, NodeKind};
+use crate::scan;
+
+/// Nodes, counting each loop iteration, evaluated before giving up.
+const STEP_BUDGET: usize = 1 << 20;
+/// Cells the evaluated prefix may touch.
+const MAX_CELLS: usize = 1 << 16;
+
+/// Runs the input-independent prefix of the program at compile time, starting
+/// from the all-zero tape. Top-level nodes are evaluated until one reads
+/// input, leaves the tape bounds above, overflows a cell in error mode or
+/// exhausts the step budget; the evaluated ones are replaced by a single
+/// `Output` of what they wrote followed by `Set`s and a `Move` recreating
+/// their tape state. A program evaluated to the end leaves only its output,
+/// encoded as the backends would write it.
+///
+/// Under a step or time limit nothing is evaluated, since steps taken at
+/// compile time would escape the limit.
+pub(crate) fn run(code: Vec<Node>, options: &Options) -> Vec<Node> {
+    if options.limits.interrupts() {
+        return code;
+    }
+    let mut machine = Machine {
+        max: options.cell.max(),
+        overflow: options.overflow,
+        utf8: options.utf8,
+        cells: Vec::new(),
+        pointer: 0,
+        output: Vec::new(),
+        steps: 0,
+        undo: Vec::new(),
+    };
+    let mut evaluated = 0;
+    for node in &code {
+        machine.undo.clear();
+        let pointer = machine.pointer;
+        let output_len = machine.output.len();
+        if machine.exec(std::slice::from_ref(node)).is_none() {
+            // roll back the partially evaluated node
+            for &(index, value) in machine.undo.iter().rev() {
+                machine.cells[index] = value;
+            }
+            machine.pointer = pointer;
+            machine.output.truncate(output_len);
+            break;
+        }
+        evaluated += 1;
+    }
+    if evaluated == 0 {
+        return code;
+    }
+
+    let span = code[0].span;
+    let mut result = Vec::new();
+    if !machine.output.is_empty() {
+        result.push(Node {
+            kind: NodeKind::Output(machine.output),
+            span,
+        });
+    }
+    let rest: Vec<Node> = code.into_iter().skip(evaluated).collect();
+    if rest.is_empty() {
+        return result;
+    }
+
+    for (index, &value) in machine.cells.iter().enumerate() {
+        if value != 0 {
+            result.push(Node {
+                kind: NodeKind::Set {
+                    offset: index as isize,
+                    value: value as i64,
+                },
+                span,
+            });
+        }
+    }
+    if machine.pointer != 0 {
+        result.push(Node {
+            kind: NodeKind::Move(machine.pointer as isize),
+            span,
+        });
+    }
+    result.extend(rest);
+    result
+}
+
+/// Evaluates nodes on a concrete tape.
+struct Machine {
+    /// Largest cell value.
+    max: u64,
+    overflow: Overflow,
+    utf8: bool,
+    cells: Vec<u64>,
+    pointer: usize,
+    output: Vec<u8>,
+    steps: usize,
+    /// Cells overwritten since the last top-level node, with their old values.
+    undo: Vec<(usize, u64)>,
+}
+
+impl Machine {
+    /// Runs `code`, returning `None` where evaluation has to stop.
+    fn exec(&mut self, code: &[Node]) -> Option<()> {
+        for node in code {
+            self.step()?;
+            match node.kind {
+                NodeKind::Add { offset, value } => {
+                    let index = self.index(offset)?;
+                    self.add(index, value as i128)?;
+                }
+                NodeKind::Set { offset, value } => {
+                    let index = self.index(offset)?;
+                    self.store(index, value as u64);
+                }
+                NodeKind::MulAdd { offset, factor } => {
+                    let counter = self.current()?;
+                    if counter != 0 {
+                        let index = self.index(offset)?;
+                        self.add(index, counter as i128 * factor as i128)?;
+                    }
+                }
+                NodeKind::Move(n) => self.pointer = self.pointer.checked_add_signed(n)?,
+                NodeKind::Scan(stride) => {
+                    self.index(0)?;
+                    let found = match scan::find_zero_scalar(&self.cells, self.pointer, stride) {
+                        Some(found) => found,
+                        // cells past the end of the tape are all zero
+                        None if stride > 0 => {
+                            let stride = stride as usize;
+                            let steps = (self.cells.len() - self.pointer).div_ceil(stride);
+                            self.pointer + steps * stride
+                        }
+                        None => return None,
+                    };
+                    let steps = found.abs_diff(self.pointer) / stride.unsigned_abs();
+                    self.steps += steps;
+                    if self.steps > STEP_BUDGET {
+                        return None;
+                    }
+                    self.pointer = found;
+                    self.index(0)?;
+                }
+                NodeKind::Write { offset } => {
+                    let index = self.index(offset)?;
+                    let mut buf = [0; 4];
+                    let bytes = cell::encode(self.cells[index], self.utf8, &mut buf);
+                    self.output.extend_from_slice(bytes);
+                }
+                NodeKind::Read { .. } => return None,
+                NodeKind::Output(ref values) => self.output.extend_from_slice(values),
+                NodeKind::Loop(ref body) => {
+                    while self.current()? != 0 {
+                        self.exec(body)?;
+                        self.step()?;
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn step(&mut self) -> Option<()> {
+        self.steps += 1;
+        (self.steps <= STEP_BUDGET).then_some(())
+    }
+
+    /// Index of the cell at `offset` from the pointer, growing the tape to
+    /// cover it.
+    fn index(&mut self, offset: isize) -> Option<usize> {
+        let index = self.pointer.checked_add_signed(offset)?;
+        if index >= MAX_CELLS {
+            return None;
+        }
+        if index >= self.cells.len() {
+            self.cells.resize(index + 1, 0);
+        }
+        Some(index)
+    }
+
+    fn current(&mut self) -> Option<u64> {
+        let index = self.index(0)?;
+        Some(self.cells[index])
+    }
+
+    /// Adds `n` to the cell at `index`, returning `None` if that overflows.
+    fn add(&mut self, index: usize, n: i128) -> Option<()> {
+        let value = self.overflow.add(self.cells[index], n, self.max)?;
+        self.store(index, value);
+        Some(())
+    }
+
+    /// Stores `value` truncated to the cell width.
+    fn store(&mut self, index: usize, value: u64) {
+        self.undo.push((index, self.cells[index]));
+        self.cells[index] = value & self.max;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Span;
+
+    fn node(kind: NodeKind) -> Node {
+        Node {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    fn add(offset: isize, value: i64) -> Node {
+        node(NodeKind::Add { offset, value })
+    }
+
+    fn set(offset: isize, value: i64) -> Node {
+        node(NodeKind::Set { offset, value })
+    }
+
+    /// `+.` followed by a loop running `body`.
+    fn prefix_then_loop(body: Vec<Node>) -> Vec<Node> {
+        vec![
+            add(0, 1),
+            node(NodeKind::Write { offset: 0 }),
+            node(NodeKind::Loop(body)),
+        ]
+    }
+
+    /// What evaluating [`prefix_then_loop`] up to its loop leaves.
+    fn evaluated_prefix(code: &[Node]) -> Vec<Node> {
+        vec![node(NodeKind::Output(vec![1])), set(0, 1), code[2].clone()]
+    }
+
+    #[test]
+    fn reads_roll_back_the_partially_evaluated_node() {
+        let code = prefix_then_loop(vec![
+            add(1, 5),
+            node(NodeKind::Write { offset: 1 }),
+            node(NodeKind::Read { offset: 0 }),
+        ]);
+        assert_eq!(
+            run(code.clone(), &Options::default()),
+            evaluated_prefix(&code)
+        );
+    }
+
+    #[test]
+    fn overflow_rolls_back_the_partially_evaluated_node() {
+        let code = prefix_then_loop(vec![
+            node(NodeKind::Move(1)),
+            add(0, 200),
+            add(0, 100),
+            node(NodeKind::Move(-1)),
+            add(0, -1),
+        ]);
+        let options = Options {
+            overflow: Overflow::Error,
+            ..Options::default()
+        };
+        assert_eq!(run(code.clone(), &options), evaluated_prefix(&code));
+
+        // when cells wrap the loop runs once, finishing the program
+        let expected = vec![node(NodeKind::Output(vec![1]))];
+        assert_eq!(run(code, &Options::default()), expected);
+    }
+
+    #[test]
+    fn exhausting_the_budget_rolls_back_the_loop() {
+        let code = prefix_then_loop(vec![add(1, 1), node(NodeKind::Write { offset: 1 })]);
+        assert_eq!(
+            run(code.clone(), &Options::default()),
+            evaluated_prefix(&code)
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use super::map_levels;
+use crate::parser::{Node, NodeKind};
+
+/// Replaces loops whose body is a single pointer move, e.g. `[>]` or
+/// `[<<<<]`, with `Scan`.
+pub(crate) fn run(code: Vec<Node>) -> Vec<Node> {
+    map_levels(code, &lower_scan_loops)
+}
+
+fn lower_scan_loops(code: Vec<Node>) -> Vec<Node> {
+    code.into_iter()
+        .map(|node| match &node.kind {
+            NodeKind::Loop(body) => match body.as_slice() {
+                [Node {
+                    kind: NodeKind::Move(n),
+                    ..
+                }] => Node {
+                    kind: NodeKind::Scan(*n),
+                    span: node.span,
+                },
+                _ => node,
+            },
+            _ => node,
+        })
+        .collect()
+}
@@ -0,0 +1,84 @@
+use super::map_levels;
+use crate::cell::Overflow;
+use crate::options::Options;
+use crate::parser::{Node, NodeKind};
+
+/// Folds runs of `+`/`-` and `>`/`<` into single nodes and drops those that
+/// cancel out. A folded node keeps the span of the first instruction in the run.
+/// Unless cells wrap, only adds of the same sign are folded, and only while
+/// the sum stays within the cell range, since `+-` on a full cell overflows.
+pub(crate) fn run(code: Vec<Node>, options: &Options) -> Vec<Node> {
+    map_levels(code, &|code| simplify(code, options))
+}
+
+fn simplify(code: Vec<Node>, options: &Options) -> Vec<Node> {
+    let mut result: Vec<Node> = Vec::new();
+    for next_op in code {
+        let prev_op = result.last().map(|node| &node.kind);
+
+        let combined = match (prev_op, &next_op.kind) {
+            (
+                Some(&NodeKind::Add { offset, value: x }),
+                &NodeKind::Add {
+                    offset: next_offset,
+                    value: y,
+                },
+            ) if offset == next_offset => {
+                fold_adds(x, y, options).map(|value| NodeKind::Add { offset, value })
+            }
+            (Some(&NodeKind::Move(x)), &NodeKind::Move(y)) => Some(NodeKind::Move(x + y)),
+            _ => None,
+        };
+
+        match combined {
+            Some(NodeKind::Add { value: 0, .. }) | Some(NodeKind::Move(0)) => {
+                result.pop();
+            }
+            Some(kind) => result.last_mut().unwrap().kind = kind,
+            None => result.push(next_op),
+        }
+    }
+    result
+}
+
+/// The single add doing the same as adding `x` and then `y`, if any.
+fn fold_adds(x: i64, y: i64, options: &Options) -> Option<i64> {
+    if options.overflow == Overflow::Wrap {
+        return Some(x.wrapping_add(y));
+    }
+    let sum = x.checked_add(y)?;
+    (x.signum() == y.signum() && sum.unsigned_abs() <= options.cell.max()).then_some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn folded_nodes_keep_the_span_of_their_first_instruction() {
+        let code = parse("+\n++>\n>[-<+>]").unwrap();
+        let code = run(code, &Options::default());
+        let spans: Vec<_> = code
+            .iter()
+            .map(|node| (node.span.line, node.span.column))
+            .collect();
+        assert_eq!(spans, [(1, 1), (2, 3), (3, 2)]);
+        let NodeKind::Loop(ref body) = code[2].kind else {
+            panic!("expected a loop, got {:?}", code[2]);
+        };
+        let spans: Vec<_> = body
+            .iter()
+            .map(|node| (node.span.line, node.span.column))
+            .collect();
+        assert_eq!(spans, [(3, 3), (3, 4), (3, 5), (3, 6)]);
+    }
+
+    #[test]
+    fn cancelling_runs_are_dropped() {
+        let code = run(parse("+-><.").unwrap(), &Options::default());
+        assert_eq!(code.len(), 1);
+        assert_eq!(code[0].kind, NodeKind::Write { offset: 0 });
+        assert_eq!(code[0].span.column, 5);
+    }
+}
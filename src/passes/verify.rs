@@ -0,0 +1,120 @@
+use crate::error::BfError;
+use crate::parser::{Node, NodeKind};
+
+/// Checks the invariants every pass must preserve, blaming `pass` for the
+/// first node that breaks one.
+pub(crate) fn run(code: &[Node], pass: &str) -> Result<(), BfError> {
+    for node in code {
+        if let Some(problem) = check(&node.kind) {
+            return Err(BfError::Compile(format!(
+                "{}: invalid IR after pass '{}': {}",
+                node.span, pass, problem
+            )));
+        }
+        if let NodeKind::Loop(body) = &node.kind {
+            run(body, pass)?;
+        }
+    }
+    Ok(())
+}
+
+fn check(kind: &NodeKind) -> Option<String> {
+    match *kind {
+        NodeKind::Add { value: 0, .. } => Some("add of zero".into()),
+        NodeKind::Move(0) => Some("move by zero".into()),
+        NodeKind::Scan(0) => Some("scan with a zero stride".into()),
+        NodeKind::MulAdd { offset: 0, .. } => Some("multiply into its own counter".into()),
+        NodeKind::MulAdd { factor: 0, .. } => Some("multiply by zero".into()),
+        NodeKind::Add { offset, .. }
+        | NodeKind::Set { offset, .. }
+        | NodeKind::MulAdd { offset, .. }
+        | NodeKind::Write { offset }
+        | NodeKind::Read { offset }
+        | NodeKind::Scan(offset)
+            if i32::try_from(offset).is_err() =>
+        {
+            Some(format!("offset {} does not fit in 32 bits", offset))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Span;
+
+    fn node(kind: NodeKind, column: usize) -> Node {
+        Node {
+            kind,
+            span: Span {
+                offset: column - 1,
+                line: 1,
+                column,
+            },
+        }
+    }
+
+    /// The message `run` reports for `kind`, nested in a loop so the whole
+    /// tree is known to be checked.
+    fn error(kind: NodeKind) -> String {
+        let code = [
+            node(NodeKind::Write { offset: 0 }, 1),
+            node(NodeKind::Loop(vec![node(kind, 3)]), 2),
+        ];
+        match run(&code, "offsets") {
+            Err(BfError::Compile(message)) => message,
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn valid_ir_passes() {
+        let code = [
+            node(
+                NodeKind::Add {
+                    offset: -1,
+                    value: 3,
+                },
+                1,
+            ),
+            node(NodeKind::Loop(vec![node(NodeKind::Move(2), 3)]), 2),
+        ];
+        assert!(run(&code, "parse").is_ok());
+    }
+
+    #[test]
+    fn moves_by_zero_are_rejected() {
+        assert_eq!(
+            error(NodeKind::Move(0)),
+            "1:3: invalid IR after pass 'offsets': move by zero"
+        );
+    }
+
+    #[test]
+    fn adds_of_zero_are_rejected() {
+        assert_eq!(
+            error(NodeKind::Add {
+                offset: 1,
+                value: 0
+            }),
+            "1:3: invalid IR after pass 'offsets': add of zero"
+        );
+    }
+
+    #[test]
+    fn offsets_over_32_bits_are_rejected() {
+        let offset = i32::MAX as isize + 1;
+        assert_eq!(
+            error(NodeKind::Set { offset, value: 1 }),
+            format!(
+                "1:3: invalid IR after pass 'offsets': offset {} does not fit in 32 bits",
+                offset
+            )
+        );
+        assert!(error(NodeKind::Write {
+            offset: i32::MIN as isize - 1
+        })
+        .contains("after pass 'offsets'"));
+    }
+}
@@ -0,0 +1,136 @@
+//! Helpers called from JIT-compiled code.
+//!
+//! Generated functions receive a pointer to a [`Context`] and pass it back to
+//! these helpers, which return a boxed [`BfError`] or null on success.
+
+use crate::cell::{self, CellWidth};
+use crate::error::BfError;
+use crate::io::{EofPolicy, Io};
+use crate::limits::Budget;
+use crate::options::Options;
+use crate::parser::Span;
+
+/// Signature of JIT-compiled functions: the address of cell 0, the runtime
+/// context, the tape length and the cell the data pointer starts at.
+pub(crate) type Entry =
+    unsafe extern "sysv64" fn(*mut u8, *mut Context, usize, usize) -> *mut BfError;
+
+pub(crate) struct Context<'a> {
+    pub io: &'a mut dyn Io,
+    pub eof: EofPolicy,
+    pub cell: CellWidth,
+    pub utf8: bool,
+    /// Output computed at compile time, written by [`write_data`].
+    pub data: &'a [u8],
+    /// What is left of the limits. Generated code charges its fuel and polls
+    /// its cancellation flag at loop back edges.
+    pub budget: Budget,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(io: &'a mut dyn Io, options: &Options, data: &'a [u8]) -> Self {
+        Context {
+            io,
+            eof: options.eof,
+            cell: options.cell,
+            utf8: options.utf8,
+            data,
+            budget: Budget::start(&options.limits),
+        }
+    }
+}
+
+fn into_raw(err: BfError) -> *mut BfError {
+    Box::into_raw(Box::new(err))
+}
+
+/// Takes ownership of an error returned by JIT-compiled code.
+pub(crate) unsafe fn take_error(error: *mut BfError) -> Result<(), BfError> {
+    if error.is_null() {
+        Ok(())
+    } else {
+        Err(*Box::from_raw(error))
+    }
+}
+
+/// Writes a cell holding `value`, zero-extended from the cell width.
+pub(crate) unsafe extern "sysv64" fn write(ctx: *mut Context, value: u64) -> *mut BfError {
+    let ctx = &mut *ctx;
+    let mut buf = [0; 4];
+    let bytes = cell::encode(value, ctx.utf8, &mut buf);
+    match ctx.budget.write(ctx.io, bytes) {
+        Err(err) => into_raw(err),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Writes `len` bytes of the context's constant data starting at `start`.
+pub(crate) unsafe extern "sysv64" fn write_data(
+    ctx: *mut Context,
+    start: usize,
+    len: usize,
+) -> *mut BfError {
+    let ctx = &mut *ctx;
+    match ctx.budget.write(ctx.io, &ctx.data[start..start + len]) {
+        Err(err) => into_raw(err),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Reads into the cell at `buf`. Generated code touches the cell before the
+/// call, so that leaving the tape faults there rather than in this helper.
+pub(crate) unsafe extern "sysv64" fn read(ctx: *mut Context, buf: *mut u8) -> *mut BfError {
+    let ctx = &mut *ctx;
+    match ctx.io.read().and_then(|input| ctx.eof.resolve(input)) {
+        Err(err) => into_raw(err.into()),
+        Ok(value) => {
+            if let Some(value) = value {
+                match ctx.cell {
+                    CellWidth::Bits8 => *buf = value as u8,
+                    CellWidth::Bits16 => buf.cast::<u16>().write_unaligned(value as u16),
+                    CellWidth::Bits32 => buf.cast::<u32>().write_unaligned(value as u32),
+                    CellWidth::Bits64 => buf.cast::<u64>().write_unaligned(value),
+                }
+            }
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Reports the step or time limit exceeded, once generated code ran out of
+/// fuel or found the run cancelled.
+pub(crate) unsafe extern "sysv64" fn limit_exceeded(ctx: *mut Context) -> *mut BfError {
+    into_raw((*ctx).budget.exceeded())
+}
+
+/// Reports an access outside the tape by the instruction at the given
+/// source position.
+pub(crate) extern "sysv64" fn out_of_bounds(
+    offset: usize,
+    line: usize,
+    column: usize,
+) -> *mut BfError {
+    into_raw(BfError::TapeOutOfBounds {
+        span: Some(Span {
+            offset,
+            line,
+            column,
+        }),
+    })
+}
+
+/// Reports arithmetic taking a cell out of its range at the given source
+/// position.
+pub(crate) extern "sysv64" fn cell_overflow(
+    offset: usize,
+    line: usize,
+    column: usize,
+) -> *mut BfError {
+    into_raw(BfError::CellOverflow {
+        span: Span {
+            offset,
+            line,
+            column,
+        },
+    })
+}
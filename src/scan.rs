@@ -0,0 +1,152 @@
+//! Searching the tape for a zero cell, as done by `Scan` nodes.
+
+use crate::cell::Cell;
+
+const LOW_BITS: u64 = 0x7f7f_7f7f_7f7f_7f7f;
+const HIGH_BITS: u64 = 0x8080_8080_8080_8080;
+
+/// Returns the index of the first zero cell among `start`, `start + stride`,
+/// `start + 2 * stride`, ... that lies inside `memory`.
+pub(crate) fn find_zero(memory: &[u8], start: usize, stride: isize) -> Option<usize> {
+    match stride {
+        1 => memchr::memchr(0, &memory[start..]).map(|i| start + i),
+        -1 => memchr::memrchr(0, &memory[..=start]),
+        2 | 4 | 8 => find_zero_forward(memory, start, stride.unsigned_abs()),
+        -2 | -4 | -8 => find_zero_backward(memory, start, stride.unsigned_abs()),
+        _ => find_zero_scalar(memory, start, stride),
+    }
+}
+
+/// Sets the high bit of every byte of `x` that is zero. Unlike the usual
+/// `(x - 0x01..) & !x` trick this is exact for every byte, not just the lowest.
+fn zero_bytes(x: u64) -> u64 {
+    !(((x & LOW_BITS) + LOW_BITS) | x) & HIGH_BITS
+}
+
+/// High bits of the bytes `0, stride, 2 * stride, ...` of a word.
+fn lane_mask(stride: usize) -> u64 {
+    (0..8)
+        .step_by(stride)
+        .fold(0, |mask, lane| mask | 0x80 << (lane * 8))
+}
+
+fn load_word(memory: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(memory[at..at + 8].try_into().unwrap())
+}
+
+fn find_zero_forward(memory: &[u8], start: usize, stride: usize) -> Option<usize> {
+    let mask = lane_mask(stride);
+    let mut i = start;
+    while i + 8 <= memory.len() {
+        let zeros = zero_bytes(load_word(memory, i)) & mask;
+        if zeros != 0 {
+            return Some(i + zeros.trailing_zeros() as usize / 8);
+        }
+        i += 8;
+    }
+    find_zero_scalar(memory, i, stride as isize)
+}
+
+/// High bits of the bytes `7, 7 - stride, 7 - 2 * stride, ...` of a word,
+/// i.e. the lanes counted down from its last byte.
+fn backward_lane_mask(stride: usize) -> u64 {
+    lane_mask(stride).reverse_bits() << 7
+}
+
+fn find_zero_backward(memory: &[u8], start: usize, stride: usize) -> Option<usize> {
+    let mask = backward_lane_mask(stride);
+    let mut end = start;
+    while end >= 7 {
+        let zeros = zero_bytes(load_word(memory, end - 7)) & mask;
+        if zeros != 0 {
+            return Some(end - zeros.leading_zeros() as usize / 8);
+        }
+        end = end.checked_sub(8)?;
+    }
+    find_zero_scalar(memory, end, -(stride as isize))
+}
+
+/// [`find_zero`] for cells of any width, one cell at a time.
+pub(crate) fn find_zero_scalar<T: Cell>(
+    memory: &[T],
+    start: usize,
+    stride: isize,
+) -> Option<usize> {
+    let mut i = start;
+    while i < memory.len() {
+        if memory[i] == T::ZERO {
+            return Some(i);
+        }
+        i = i.checked_add_signed(stride)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_bytes_marks_exactly_the_zero_bytes() {
+        assert_eq!(zero_bytes(0), HIGH_BITS);
+        assert_eq!(zero_bytes(u64::MAX), 0);
+        // a zero next to 0x01 or 0x80 must not leak into its neighbours
+        assert_eq!(zero_bytes(0x0100_8001_0080_0100), 0x0080_0000_8000_0080);
+        for byte in 1..=255u64 {
+            assert_eq!(
+                zero_bytes(byte << 24),
+                HIGH_BITS & !(0x80 << 24),
+                "{:#x}",
+                byte
+            );
+        }
+    }
+
+    #[test]
+    fn lane_masks_select_every_stride_th_byte() {
+        assert_eq!(lane_mask(1), HIGH_BITS);
+        assert_eq!(lane_mask(2), 0x0080_0080_0080_0080);
+        assert_eq!(lane_mask(4), 0x0000_0080_0000_0080);
+        assert_eq!(lane_mask(8), 0x0000_0000_0000_0080);
+    }
+
+    #[test]
+    fn backward_lane_masks_start_at_the_last_byte() {
+        assert_eq!(backward_lane_mask(1), HIGH_BITS);
+        assert_eq!(backward_lane_mask(2), 0x8000_8000_8000_8000);
+        assert_eq!(backward_lane_mask(4), 0x8000_0000_8000_0000);
+        assert_eq!(backward_lane_mask(8), 0x8000_0000_0000_0000);
+    }
+
+    #[test]
+    fn find_zero_agrees_with_scalar_search() {
+        // pseudo-random tapes with roughly one zero cell in eight
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for len in 1..48 {
+            let memory: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    if seed.is_multiple_of(8) {
+                        0
+                    } else {
+                        seed as u8 | 1
+                    }
+                })
+                .collect();
+            for stride in [1, 2, 3, 4, 8, -1, -2, -3, -4, -8] {
+                for start in 0..len {
+                    assert_eq!(
+                        find_zero(&memory, start, stride),
+                        find_zero_scalar(&memory, start, stride),
+                        "{:?} from {} by {}",
+                        memory,
+                        start,
+                        stride
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::cell::Cell;
+use crate::error::BfError;
+use crate::options::Options;
+use crate::INIT_MEMORY_SIZE;
+#[cfg(unix)]
+use memmap2::UncheckedAdvice;
+use memmap2::{MmapMut, MmapOptions};
+use std::io;
+use std::ops::{Index, IndexMut, Range, RangeInclusive};
+use std::slice;
+
+/// Default size in bytes of the guard regions on each side of the cells.
+const DEFAULT_GUARD: usize = 1 << 16;
+/// Largest guard region in bytes. Code that may jump further past the end of
+/// the tape checks its accesses instead.
+pub(crate) const MAX_GUARD: usize = 1 << 32;
+/// Cells a reserved tape has on each side of its origin, where the address
+/// space allows.
+pub const RESERVED_CELLS: usize = 1 << 32;
+/// Fewest cells a reserved tape is cut to when the address space is limited.
+const MIN_RESERVED_CELLS: usize = 1 << 20;
+/// Cells of a circular tape unless a size is given, as in the classic
+/// implementation.
+pub const CIRCULAR_TAPE_SIZE: usize = 30000;
+
+/// How the data pointer behaves at the ends of the tape.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TapeMode {
+    /// Cells start at the origin and extend to the right without bound.
+    #[default]
+    RightInfinite,
+    /// Cells extend without bound in both directions.
+    Bidirectional,
+    /// The pointer wraps around at either end of a tape of fixed size.
+    Circular,
+}
+
+/// The memory cells a program operates on.
+///
+/// Cells live in an anonymous mapping between two inaccessible guard
+/// regions, so JIT-compiled code running off either end of the tape faults
+/// instead of corrupting other memory. The mapping is rounded up to whole
+/// pages, leaving unused zero cells between the last cell and the upper
+/// guard region until generated code claims them.
+///
+/// Memory is committed by the system when a page is first touched, so a
+/// tape only costs what the program actually uses.
+///
+/// Lengths and the origin count bytes, which are cells of the default width.
+/// Wider cells span several consecutive bytes.
+pub struct Tape {
+    map: MmapMut,
+    len: usize,
+    /// Bytes of each guard region.
+    guard: usize,
+    /// Offset of the cell the data pointer starts at.
+    origin: usize,
+}
+
+impl Tape {
+    pub fn new(size: usize) -> Result<Tape, BfError> {
+        Tape::with_origin(size, 0)
+    }
+
+    /// A tape of `size` bytes whose data pointer starts at byte `origin`, so
+    /// that programs moving left first have room to do so.
+    pub fn with_origin(size: usize, origin: usize) -> Result<Tape, BfError> {
+        if origin >= size {
+            return Err(BfError::Memory(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tape origin {} is outside a tape of {} bytes", origin, size),
+            )));
+        }
+        Ok(Tape {
+            map: map_cells(size.next_multiple_of(page_size()), DEFAULT_GUARD)?,
+            len: size,
+            guard: DEFAULT_GUARD,
+            origin,
+        })
+    }
+
+    /// The tape JIT-compiled code for a program with the tape range `range`
+    /// runs on, as `options` lay it out: `tape_size` cells if given, and
+    /// otherwise
+    ///
+    /// - for a right-infinite tape, the cells the program provably stays
+    ///   within, or a reserved tape when that is unknown;
+    /// - for a bidirectional tape, a reserved tape starting in its middle;
+    /// - for a circular tape, [`CIRCULAR_TAPE_SIZE`] cells.
+    ///
+    /// Tapes that would be larger are cut to the tape limit. Reserved tapes
+    /// get guard regions of `guard` bytes, so that preparing them for the
+    /// code never moves their cells.
+    pub(crate) fn for_code(
+        range: Option<RangeInclusive<isize>>,
+        options: &Options,
+        guard: usize,
+    ) -> Result<Tape, BfError> {
+        // tapes are sized in bytes
+        let bytes = options.cell.bytes();
+        let origin = options.tape_origin.saturating_mul(bytes);
+        let max = options.limits.tape_bytes(options.cell);
+        match (options.tape, options.tape_size) {
+            (_, Some(size)) => Tape::with_origin(size.saturating_mul(bytes), origin),
+            (TapeMode::RightInfinite, None) => match (known_len(range, options), max) {
+                (Some(size), max) => {
+                    Tape::with_origin(max.map_or(size, |max| size.min(max)), origin)
+                }
+                (None, Some(max)) => Tape::with_origin(max, origin),
+                (None, None) => Tape::reserve(origin, false, guard),
+            },
+            // the whole tape allowed, with the origin in its middle
+            (TapeMode::Bidirectional, None) => match max {
+                Some(max) => Tape::with_origin(max, max / bytes / 2 * bytes),
+                None => Tape::reserve(0, true, guard),
+            },
+            (TapeMode::Circular, None) => Tape::with_origin(CIRCULAR_TAPE_SIZE * bytes, origin),
+        }
+    }
+
+    /// The tape the interpreter starts a program with the tape range `range`
+    /// on, as `options` lay it out: `tape_size` cells if given, and otherwise
+    /// the cells the program provably stays within to the right of its
+    /// origin, or a small tape the interpreter grows as the program needs.
+    pub(crate) fn growable(
+        range: Option<RangeInclusive<isize>>,
+        options: &Options,
+    ) -> Result<Tape, BfError> {
+        let bytes = options.cell.bytes();
+        let origin = options.tape_origin.saturating_mul(bytes);
+        let size = match (options.tape, options.tape_size) {
+            (_, Some(size)) => size.saturating_mul(bytes),
+            (TapeMode::Circular, None) => CIRCULAR_TAPE_SIZE * bytes,
+            (_, None) => {
+                let size = known_len(range, options)
+                    .unwrap_or(INIT_MEMORY_SIZE.next_multiple_of(bytes).max(origin + bytes));
+                let max = options.limits.tape_bytes(options.cell);
+                max.map_or(size, |max| size.min(max))
+            }
+        };
+        Tape::with_origin(size, origin)
+    }
+
+    /// A reserved tape, which JIT-compiled code sees as unbounded: a tape of
+    /// [`RESERVED_CELLS`] cells on each side of its origin, or, unless
+    /// `both_ways`, past its origin at byte `origin`.
+    ///
+    /// Where the address space is too small for that, e.g. under `ulimit -v`,
+    /// the cells reserved are halved until the mapping fits. Generated code
+    /// then leaves the smaller tape through its guard regions like any other.
+    pub fn reserved(origin: usize, both_ways: bool) -> Result<Tape, BfError> {
+        Tape::reserve(origin, both_ways, DEFAULT_GUARD)
+    }
+
+    /// [`Tape::reserved`] with guard regions of at least `guard` bytes.
+    fn reserve(origin: usize, both_ways: bool, guard: usize) -> Result<Tape, BfError> {
+        let guard = guard.max(DEFAULT_GUARD).next_multiple_of(page_size());
+        let mut cells = RESERVED_CELLS;
+        loop {
+            let (len, origin) = match both_ways {
+                true => (2 * cells, cells),
+                false => (origin.saturating_add(cells), origin),
+            };
+            match map_cells(len.next_multiple_of(page_size()), guard) {
+                Ok(map) => {
+                    return Ok(Tape {
+                        map,
+                        len,
+                        guard,
+                        origin,
+                    })
+                }
+                Err(_) if cells > MIN_RESERVED_CELLS => cells /= 2,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    pub fn cells(&self) -> &[u8] {
+        &self.map[self.guard..self.guard + self.len]
+    }
+
+    pub(crate) fn cells_mut(&mut self) -> &mut [u8] {
+        &mut self.map[self.guard..self.guard + self.len]
+    }
+
+    /// The tape as cells of type `T`, which must divide its length.
+    pub(crate) fn cells_of<T: Cell>(&self) -> &[T] {
+        let cells = self.cells();
+        // the mapping is page aligned and guard regions are whole pages
+        unsafe { slice::from_raw_parts(cells.as_ptr().cast(), cells.len() / size_of::<T>()) }
+    }
+
+    pub(crate) fn cells_of_mut<T: Cell>(&mut self) -> &mut [T] {
+        let cells = self.cells_mut();
+        unsafe {
+            slice::from_raw_parts_mut(cells.as_mut_ptr().cast(), cells.len() / size_of::<T>())
+        }
+    }
+
+    /// Resets every cell to zero, releasing the memory behind them.
+    pub fn clear(&mut self) {
+        if release(&self.map, self.guard, self.capacity()).is_err() {
+            self.cells_mut().fill(0);
+        }
+    }
+
+    /// Cells that fit between the guard regions.
+    fn capacity(&self) -> usize {
+        self.map.len() - 2 * self.guard
+    }
+
+    /// Grows the tape with zero cells to at least `len` cells.
+    pub(crate) fn ensure_len(&mut self, len: usize) -> Result<(), BfError> {
+        if self.len < len {
+            if self.capacity() < len {
+                self.remap(len.next_multiple_of(page_size()), self.guard, 0)?;
+            }
+            self.len = len;
+        }
+        Ok(())
+    }
+
+    /// Adds `cells` zero cells in front of the tape, shifting every cell and
+    /// the origin up by as much.
+    pub(crate) fn grow_front(&mut self, cells: usize) -> Result<(), BfError> {
+        let len = self.len + cells;
+        self.remap(len.next_multiple_of(page_size()), self.guard, cells)?;
+        self.len = len;
+        self.origin += cells;
+        Ok(())
+    }
+
+    /// Extends the tape over the unused cells below the upper guard region,
+    /// so that leaving the tape on either side faults.
+    pub(crate) fn fill_capacity(&mut self) {
+        self.len = self.capacity();
+    }
+
+    /// Widens both guard regions to at least `guard` bytes.
+    pub(crate) fn ensure_guard(&mut self, guard: usize) -> Result<(), BfError> {
+        if self.guard < guard {
+            self.remap(self.capacity(), guard.next_multiple_of(page_size()), 0)?;
+        }
+        Ok(())
+    }
+
+    /// Address ranges of the guard regions below and above the cells.
+    pub(crate) fn guard_regions(&self) -> [Range<usize>; 2] {
+        let cells = self.map.as_ptr() as usize + self.guard;
+        let end = cells + self.capacity();
+        [cells - self.guard..cells, end..end + self.guard]
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.cells_mut().as_mut_ptr()
+    }
+
+    /// Moves the cells to a new mapping with room for `capacity` cells, placing
+    /// them `front` cells after its start.
+    fn remap(&mut self, capacity: usize, guard: usize, front: usize) -> Result<(), BfError> {
+        let mut map = map_cells(capacity, guard)?;
+        let start = guard + front;
+        map[start..start + self.len].copy_from_slice(self.cells());
+        self.map = map;
+        self.guard = guard;
+        Ok(())
+    }
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape::new(INIT_MEMORY_SIZE).expect("failed to map the tape")
+    }
+}
+
+impl Index<usize> for Tape {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        &self.cells()[index]
+    }
+}
+
+impl IndexMut<usize> for Tape {
+    fn index_mut(&mut self, index: usize) -> &mut u8 {
+        &mut self.cells_mut()[index]
+    }
+}
+
+/// Bytes of the right-infinite tape a program with the tape range `range`
+/// provably stays within, if it never moves left of the origin.
+fn known_len(range: Option<RangeInclusive<isize>>, options: &Options) -> Option<usize> {
+    range
+        .filter(|range| {
+            options
+                .tape_origin
+                .checked_add_signed(*range.start())
+                .is_some()
+        })
+        .map(|range| (options.tape_origin + *range.end() as usize + 1) * options.cell.bytes())
+}
+
+/// Maps `len` zero cells between two inaccessible regions of `guard` bytes,
+/// without reserving swap space for them. Both must be multiples of the page
+/// size.
+fn map_cells(len: usize, guard: usize) -> Result<MmapMut, BfError> {
+    let map = MmapOptions::new()
+        .len(guard + len + guard)
+        .no_reserve_swap()
+        .map_anon()
+        .map_err(BfError::Memory)?;
+    protect(&map, 0, guard).map_err(BfError::Memory)?;
+    protect(&map, guard + len, guard).map_err(BfError::Memory)?;
+    Ok(map)
+}
+
+#[cfg(unix)]
+fn protect(map: &MmapMut, offset: usize, len: usize) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let address = unsafe { map.as_ptr().add(offset) } as *mut libc::c_void;
+    if unsafe { libc::mprotect(address, len, libc::PROT_NONE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Leaves the guard regions accessible. Generated code checks its accesses
+/// on these platforms instead of relying on them.
+#[cfg(not(unix))]
+fn protect(_map: &MmapMut, _offset: usize, _len: usize) -> io::Result<()> {
+    Ok(())
+}
+
+/// Gives the pages of `len` bytes at `offset` back to the system, which
+/// zeroes them when they are touched again.
+#[cfg(unix)]
+fn release(map: &MmapMut, offset: usize, len: usize) -> io::Result<()> {
+    unsafe { map.unchecked_advise_range(UncheckedAdvice::DontNeed, offset, len) }
+}
+
+#[cfg(not(unix))]
+fn release(_map: &MmapMut, _offset: usize, _len: usize) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
@@ -0,0 +1,109 @@
+//! Runs the `bfvm` binary for behaviour only the command line has.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Writes `source` to a file of its own in the temporary directory.
+fn source_file(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("bfvm-{}-{}.bf", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+/// Runs `bfvm` with `args` and `input` on its standard input.
+fn bfvm(args: &[&str], path: &PathBuf, input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bfvm"))
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the program may exit without reading its input
+    let _ = child.stdin.take().unwrap().write_all(input);
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn emit_prints_without_running_the_program() {
+    let path = source_file("emit", "+[,.]");
+    for args in [
+        &["--emit=ir"][..],
+        &["--emit=clif"],
+        &["--emit=asm", "--backend=fast-jit"],
+        &["--emit=asm", "--backend=crane-jit"],
+        &["--emit=bytes", "--backend=fast-jit"],
+    ] {
+        let output = bfvm(args, &path, b"secret");
+        assert!(output.status.success(), "{:?}: {:?}", args, output);
+        assert!(!output.stdout.is_empty(), "{:?} printed nothing", args);
+        assert!(
+            !output.stdout.windows(6).any(|window| window == b"secret"),
+            "{:?} ran the program",
+            args
+        );
+    }
+
+    // the interpreter has no machine code to show
+    let output = bfvm(&["--emit=asm"], &path, b"");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn emit_ir_shows_the_optimized_nodes() {
+    let path = source_file("emit-ir", "++\n[-]>.");
+    let output = bfvm(&["--emit=ir", "-O1"], &path, b"");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "     2:4  move 1\n     2:5  write [0]\n"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_with_limited_address_space() {
+    use std::os::unix::process::CommandExt;
+
+    let path = source_file("address-space", ">,[>,]<[.<]");
+    for backend in ["interpreter", "fast-jit", "crane-jit"] {
+        for tape in ["right-infinite", "bidirectional"] {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_bfvm"));
+            command
+                .args(["--backend", backend, "--tape", tape])
+                .arg(&path)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            // as `ulimit -v 2000000`, far less than a reserved tape
+            unsafe {
+                command.pre_exec(|| {
+                    let limit = libc::rlimit {
+                        rlim_cur: 2_000_000 * 1024,
+                        rlim_max: 2_000_000 * 1024,
+                    };
+                    match libc::setrlimit(libc::RLIMIT_AS, &limit) {
+                        0 => Ok(()),
+                        _ => Err(std::io::Error::last_os_error()),
+                    }
+                });
+            }
+            let mut child = command.spawn().unwrap();
+            child.stdin.take().unwrap().write_all(b"hello").unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert!(
+                output.status.success(),
+                "{} {}: {:?}",
+                backend,
+                tape,
+                output
+            );
+            assert_eq!(output.stdout, b"olleh", "{} {}", backend, tape);
+        }
+    }
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,368 @@
+//! Runs programs in process on every backend and compares what they write
+//! with golden output.
+
+use bfvm::passes::{PassSet, MAX_OPT_LEVEL};
+use bfvm::{
+    BackendKind, BfError, CellWidth, EofPolicy, Limit, Limits, Options, Overflow, TapeMode,
+};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const BACKENDS: [BackendKind; 3] = [
+    BackendKind::Interpreter,
+    BackendKind::FastJit,
+    BackendKind::CraneJit,
+];
+
+/// Runs `source` with `input` on `backend`, with the passes of `-O<level>`.
+fn run(
+    backend: BackendKind,
+    level: u8,
+    source: &str,
+    input: &[u8],
+    options: &Options,
+) -> Result<Vec<u8>, BfError> {
+    let options = Options {
+        passes: PassSet::for_level(level),
+        ..*options
+    };
+    backend.compile(source, &options)?.run_with_input(input)
+}
+
+/// Backends able to run programs with `options`; only the interpreter
+/// supports circular tapes.
+fn backends(options: &Options) -> &'static [BackendKind] {
+    match options.tape {
+        TapeMode::Circular => &BACKENDS[..1],
+        _ => &BACKENDS,
+    }
+}
+
+/// Checks that `source` writes `expected` for `input` on every backend at
+/// every optimization level.
+fn check(source: &str, input: &[u8], options: &Options, expected: &[u8]) {
+    for &backend in backends(options) {
+        for level in 0..=MAX_OPT_LEVEL {
+            match run(backend, level, source, input, options) {
+                Ok(output) => assert_eq!(
+                    output, expected,
+                    "{:?} -O{} running {:?} with {:?}",
+                    backend, level, source, options
+                ),
+                Err(err) => panic!(
+                    "{:?} -O{} running {:?} with {:?} failed: {}",
+                    backend, level, source, options, err
+                ),
+            }
+        }
+    }
+}
+
+/// Checks that `source` fails with an error matching `expected` on every
+/// backend at every optimization level.
+fn check_error(source: &str, options: &Options, expected: fn(&BfError) -> bool) {
+    for &backend in backends(options) {
+        for level in 0..=MAX_OPT_LEVEL {
+            match run(backend, level, source, b"", options) {
+                Err(err) => assert!(
+                    expected(&err),
+                    "{:?} -O{} running {:?} failed with {:?}",
+                    backend,
+                    level,
+                    source,
+                    err
+                ),
+                Ok(output) => panic!(
+                    "{:?} -O{} running {:?} with {:?} wrote {:?} instead of failing",
+                    backend, level, source, options, output
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn golden_files() {
+    let mut checked = 0;
+    for entry in fs::read_dir("test").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|extension| extension != "bf") {
+            continue;
+        }
+        let source = fs::read_to_string(&path).unwrap();
+        let expected = fs::read(path.with_extension("out")).unwrap();
+        // some programs take minutes to interpret without optimizations
+        for backend in BACKENDS {
+            let output = run(backend, MAX_OPT_LEVEL, &source, b"", &Options::default());
+            assert!(
+                output.as_deref().is_ok_and(|output| output == expected),
+                "{:?} running {}",
+                backend,
+                path.display()
+            );
+        }
+        checked += 1;
+    }
+    assert!(
+        checked >= 2,
+        "no golden files in {}",
+        Path::new("test").display()
+    );
+}
+
+#[test]
+fn hello_world_at_every_level() {
+    let source = fs::read_to_string("test/hello_world.bf").unwrap();
+    check(&source, b"", &Options::default(), b"Hello World!\n");
+}
+
+#[test]
+fn loops() {
+    let options = Options::default();
+    // loops entered with their cell zero never run
+    check("[.]-[-]++.", b"", &options, b"\x02");
+    check("++++++[>+++++++++++<-]>-.", b"", &options, b"A");
+    // scan loops, also with strides other than one
+    check("+>+>+<<[>]<.", b"", &options, b"\x01");
+    check(">>+>>+>>+[<<]>>.", b"", &options, b"\x01");
+}
+
+#[test]
+fn echo() {
+    let options = Options::default();
+    check(",[.,]", b"echo", &options, b"echo");
+    check(">,[>,]<[.<]", b"abc", &options, b"cba");
+}
+
+#[test]
+fn eof_policies() {
+    let with_eof = |eof| Options {
+        eof,
+        ..Options::default()
+    };
+    check("+,.", b"", &with_eof(EofPolicy::Zero), b"\x00");
+    check("+,.", b"", &with_eof(EofPolicy::Max), b"\xff");
+    check("+,.", b"", &with_eof(EofPolicy::Unchanged), b"\x01");
+    check("+,.", b"x", &with_eof(EofPolicy::Error), b"x");
+    check_error("+,.", &with_eof(EofPolicy::Error), |err| {
+        matches!(err, BfError::Io(_))
+    });
+
+    let wide = Options {
+        eof: EofPolicy::Max,
+        cell: CellWidth::Bits16,
+        ..Options::default()
+    };
+    // a 16-bit cell read at EOF holds 0xffff, which moves over one unit at
+    // a time and is written as its low byte
+    check(",[>+<-]>.", b"", &wide, b"\xff");
+    check(",+.", b"", &wide, b"\x00");
+}
+
+#[test]
+fn cell_widths() {
+    // 256 is zero only in 8-bit cells
+    let source = format!("{}[>+<[-]]>.", "+".repeat(256));
+    for (cell, expected) in [
+        (CellWidth::Bits8, b"\x00"),
+        (CellWidth::Bits16, b"\x01"),
+        (CellWidth::Bits32, b"\x01"),
+        (CellWidth::Bits64, b"\x01"),
+    ] {
+        let options = Options {
+            cell,
+            ..Options::default()
+        };
+        check(&source, b"", &options, expected);
+        check("-.", b"", &options, b"\xff");
+    }
+
+    let utf8 = Options {
+        cell: CellWidth::Bits32,
+        utf8: true,
+        ..Options::default()
+    };
+    // U+263A is 38 * 256 + 58
+    let smiley = format!(
+        "{}[>{}<-]>{}.",
+        "+".repeat(38),
+        "+".repeat(256),
+        "+".repeat(58)
+    );
+    check(&smiley, b"", &utf8, "\u{263a}".as_bytes());
+    check("-.", b"", &utf8, "\u{fffd}".as_bytes());
+}
+
+#[test]
+fn overflow_modes() {
+    let with_overflow = |overflow| Options {
+        overflow,
+        ..Options::default()
+    };
+    let many = format!("{}.", "+".repeat(300));
+    check("-.", b"", &with_overflow(Overflow::Wrap), b"\xff");
+    check(&many, b"", &with_overflow(Overflow::Wrap), &[44]);
+    check("-.", b"", &with_overflow(Overflow::Saturate), b"\x00");
+    check(&many, b"", &with_overflow(Overflow::Saturate), b"\xff");
+    check("++[-].", b"", &with_overflow(Overflow::Error), b"\x00");
+    check_error("-.", &with_overflow(Overflow::Error), |err| {
+        matches!(err, BfError::CellOverflow { .. })
+    });
+    check_error(&many, &with_overflow(Overflow::Error), |err| {
+        matches!(err, BfError::CellOverflow { .. })
+    });
+    // the multiply loop overflows its target on the third iteration
+    check_error(
+        &format!(">{}<+++[->{}<]", "+".repeat(100), "+".repeat(60)),
+        &with_overflow(Overflow::Error),
+        |err| matches!(err, BfError::CellOverflow { .. }),
+    );
+}
+
+#[test]
+fn tape_modes() {
+    let with_tape = |tape| Options {
+        tape,
+        ..Options::default()
+    };
+    check_error("<+.", &with_tape(TapeMode::RightInfinite), |err| {
+        matches!(err, BfError::TapeOutOfBounds { .. })
+    });
+    check("<+.", b"", &with_tape(TapeMode::Bidirectional), b"\x01");
+    // the scan runs past the cells in front of the origin
+    check(
+        "+<+<+[<]>.",
+        b"",
+        &with_tape(TapeMode::Bidirectional),
+        b"\x01",
+    );
+    // moving left of the first cell wraps to the last one
+    check("<+>.<.", b"", &with_tape(TapeMode::Circular), b"\x00\x01");
+
+    let circular = Options {
+        tape: TapeMode::Circular,
+        tape_size: Some(2),
+        ..Options::default()
+    };
+    check(">>+<<.", b"", &circular, b"\x01");
+    check("<+>>.", b"", &circular, b"\x01");
+
+    // a given size is fixed, whether or not the program's range is known
+    for tape in [TapeMode::RightInfinite, TapeMode::Bidirectional] {
+        let fixed = Options {
+            tape,
+            tape_size: Some(5),
+            limits: Limits {
+                steps: Some(100_000),
+                ..Limits::default()
+            },
+            ..Options::default()
+        };
+        check(">>>>+.", b"", &fixed, b"\x01");
+        for source in ["+[>+]", ">>>>>+", "<+"] {
+            check_error(source, &fixed, |err| {
+                matches!(err, BfError::TapeOutOfBounds { .. })
+            });
+        }
+    }
+}
+
+#[test]
+fn limits() {
+    let with_limits = |limits| Options {
+        limits,
+        ..Options::default()
+    };
+    let steps = Limits {
+        steps: Some(1000),
+        ..Limits::default()
+    };
+    check_error("+[]", &with_limits(steps), |err| {
+        matches!(err, BfError::LimitExceeded(_))
+    });
+    check_error("+[>+<]", &with_limits(steps), |err| {
+        matches!(err, BfError::LimitExceeded(_))
+    });
+    let output = Limits {
+        output: Some(3),
+        ..Limits::default()
+    };
+    check_error("+.....", &with_limits(output), |err| {
+        matches!(err, BfError::LimitExceeded(_))
+    });
+    check("+...", b"", &with_limits(output), b"\x01\x01\x01");
+
+    // the tape limit counts cells, whatever their width
+    let tape = Options {
+        cell: CellWidth::Bits16,
+        ..with_limits(Limits {
+            tape: Some(3),
+            ..Limits::default()
+        })
+    };
+    check(",>>+.", b"", &tape, b"\x01");
+    check_error(",>>>+.", &tape, |err| {
+        matches!(err, BfError::LimitExceeded(Limit::Tape(3)))
+    });
+
+    // a scan around a circular tape without a zero cell never ends
+    let circular = Options {
+        tape: TapeMode::Circular,
+        tape_size: Some(3),
+        ..with_limits(steps)
+    };
+    check_error("+>+>+[>]", &circular, |err| {
+        matches!(err, BfError::LimitExceeded(_))
+    });
+    let time = Limits {
+        time: Some(Duration::from_millis(100)),
+        ..Limits::default()
+    };
+    let circular = Options {
+        limits: time,
+        ..circular
+    };
+    check_error("+>+>+[>]", &circular, |err| {
+        matches!(err, BfError::LimitExceeded(_))
+    });
+}
+
+/// Line and column `source` fails at with `TapeOutOfBounds` on `backend` at
+/// `-O<level>`.
+fn out_of_bounds_at(
+    backend: BackendKind,
+    level: u8,
+    source: &str,
+    options: &Options,
+) -> (usize, usize) {
+    match run(backend, level, source, b"", options) {
+        Err(BfError::TapeOutOfBounds { span: Some(span) }) => (span.line, span.column),
+        result => panic!(
+            "{:?} -O{} running {:?} gave {:?}",
+            backend, level, source, result
+        ),
+    }
+}
+
+#[test]
+fn checked_errors_are_located() {
+    let checked = Options {
+        checked: true,
+        ..Options::default()
+    };
+    for backend in BACKENDS {
+        // the pointer leaves the tape without touching a cell there
+        assert_eq!(out_of_bounds_at(backend, 0, "+\n<>.", &checked), (2, 1));
+        // offsets fold the moves into an access below the tape
+        assert_eq!(
+            out_of_bounds_at(backend, MAX_OPT_LEVEL, "+.\n<+>.", &checked),
+            (2, 2)
+        );
+        // an access in a loop body whose moves were folded away
+        assert_eq!(
+            out_of_bounds_at(backend, MAX_OPT_LEVEL, "+[\n>+<<<<-\n]", &checked),
+            (2, 7)
+        );
+    }
+}